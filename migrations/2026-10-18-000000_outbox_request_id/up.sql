@@ -0,0 +1,2 @@
+-- `outbox` itself is created by each service's own migrations.
+ALTER TABLE outbox ADD COLUMN IF NOT EXISTS request_id TEXT;
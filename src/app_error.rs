@@ -1,16 +1,35 @@
-use std::error::Error;
+use std::{backtrace::BacktraceStatus, error::Error, sync::RwLock, time::Duration};
 
 use axum::{
     Json,
-    http::StatusCode,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
+use chrono::Utc;
+use diesel::result::DatabaseErrorKind;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, warn};
 use utoipa::ToSchema;
+use uuid::Uuid;
 
-use crate::aliases::DieselError;
+use crate::{
+    aliases::DieselError,
+    config::{ErrorFormat, Errors},
+    error_reporter::{self, ErrorReport},
+    request_id::RequestId,
+};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+pub const ERROR_ID_HEADER: HeaderName = HeaderName::from_static("x-error-id");
+
+static ERROR_RESPONSES: RwLock<Option<Errors>> = RwLock::new(None);
+
+/// Sets how error responses are rendered; `bootstrap` calls it from the config.
+pub fn set_error_responses(errors: Errors) {
+    *ERROR_RESPONSES.write().unwrap_or_else(|e| e.into_inner()) = Some(errors);
+}
 
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct StdResponse<T: Serialize + ToSchema, M: ToString> {
@@ -24,6 +43,121 @@ impl<T: Serialize + ToSchema, M: ToString + Serialize> IntoResponse for StdRespo
     }
 }
 
+impl<T: Serialize + ToSchema, M: ToString> StdResponse<T, M> {
+    /// Sends this response with `status` instead of 200.
+    pub fn with_status(self, status: StatusCode) -> WithStatus<T, M> {
+        WithStatus {
+            status,
+            headers: HeaderMap::new(),
+            body: Some(self),
+        }
+    }
+}
+
+impl<T: Serialize + ToSchema> StdResponse<T, String> {
+    pub fn ok(data: T) -> Self {
+        Self {
+            data: Some(data),
+            message: None,
+        }
+    }
+
+    /// 201 with a `Location` header pointing at the new resource.
+    pub fn created(data: T, location: &str) -> WithStatus<T, String> {
+        Self::ok(data)
+            .with_status(StatusCode::CREATED)
+            .header(header::LOCATION, location)
+    }
+
+    /// 202, for work that continues after the response, e.g. through the outbox.
+    pub fn accepted(data: T) -> WithStatus<T, String> {
+        Self::ok(data).with_status(StatusCode::ACCEPTED)
+    }
+
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+}
+
+impl StdResponse<(), String> {
+    /// 204 without a body.
+    pub fn no_content() -> WithStatus<(), String> {
+        WithStatus {
+            status: StatusCode::NO_CONTENT,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+}
+
+/// A `StdResponse` sent with a specific status code and extra headers.
+pub struct WithStatus<T: Serialize + ToSchema, M: ToString> {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Option<StdResponse<T, M>>,
+}
+
+impl<T: Serialize + ToSchema, M: ToString> WithStatus<T, M> {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Adds a header. A value that is not a valid header value is dropped with a warning.
+    pub fn header(mut self, name: HeaderName, value: &str) -> Self {
+        match HeaderValue::from_str(value) {
+            Ok(value) => {
+                self.headers.append(name, value);
+            }
+            Err(_) => warn!("Dropping invalid value of response header {}", name),
+        }
+        self
+    }
+}
+
+impl<T: Serialize + ToSchema, M: ToString + Serialize> IntoResponse for WithStatus<T, M> {
+    fn into_response(self) -> Response {
+        match self.body {
+            Some(body) => (self.status, self.headers, Json(body)).into_response(),
+            None => (self.status, self.headers).into_response(),
+        }
+    }
+}
+
+/// RFC 7807 error body, sent instead of `StdResponse` when `ERROR_FORMAT=problem`.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Stable, machine-readable error code, e.g. `not_found`.
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Id of a server error in the logs and error reports.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<String>,
+    /// Field errors of a `validation_failed` error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+/// A rule a request field did not satisfy.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FieldError {
+    /// Path of the field, e.g. `address.city` or `items[0].quantity`.
+    pub field: String,
+    /// The rule that failed, e.g. `length` or `email`.
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Service {0} is unreachable")]
@@ -37,48 +171,286 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
 
+    /// The caller is not authenticated. Sent with a `WWW-Authenticate: Bearer` challenge.
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("Forbidden resource: {0}")]
     ForbiddenResource(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /// The request is well-formed but cannot be processed, e.g. it references a row
+    /// that does not exist.
+    #[error("Unprocessable entity: {0}")]
+    UnprocessableEntity(String),
+
+    /// Sent with `Retry-After` when the wait is known.
+    #[error("Too many requests")]
+    TooManyRequests { retry_after: Option<Duration> },
+
+    #[error("Validation failed for {} field(s)", .0.len())]
+    ValidationFailed(Vec<FieldError>),
+
+    /// A failure that is expected to go away, e.g. a serialization failure or deadlock;
+    /// the client may retry the request as is.
+    #[error("Temporarily unavailable, please retry: {0}")]
+    Retryable(String),
+
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::ServiceUnreachable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::ForbiddenResource(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Retryable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the same request may succeed if simply tried again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, AppError::Retryable(_))
+    }
+
+    /// Stable code clients can match on instead of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::ServiceUnreachable(_) => "service_unreachable",
+            AppError::NotFound => "not_found",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::ForbiddenResource(_) => "forbidden",
+            AppError::Conflict(_) => "conflict",
+            AppError::UnprocessableEntity(_) => "unprocessable_entity",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::ValidationFailed(_) => "validation_failed",
+            AppError::Retryable(_) => "retryable",
+            AppError::Other(_) => "internal",
+        }
+    }
+
+    /// The message sent to the client; internal errors are not described.
+    fn public_message(&self, error_id: Option<&str>) -> String {
+        match (self, error_id) {
+            (AppError::Other(_), Some(error_id)) => {
+                format!("Internal server error (error id {})", error_id)
+            }
+            (AppError::Other(_), None) => "Internal server error".into(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// The error followed by each of its causes, including anyhow context.
+    fn chain(&self) -> Vec<String> {
+        match self {
+            AppError::Other(e) => e.chain().map(|cause| cause.to_string()).collect(),
+            _ => {
+                let mut chain = vec![self.to_string()];
+                let mut source = self.source();
+                while let Some(cause) = source {
+                    chain.push(cause.to_string());
+                    source = cause.source();
+                }
+                chain
+            }
+        }
+    }
+
+    fn backtrace(&self) -> Option<String> {
+        match self {
+            AppError::Other(e) if e.backtrace().status() == BacktraceStatus::Captured => {
+                Some(e.backtrace().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Logs the error. Server errors get an id, are logged with their full cause chain
+    /// and are sent to the registered `ErrorReporter`; the id is returned.
+    fn log(&self) -> Option<String> {
+        let status = self.status();
+        let request_id = RequestId::current().map(|id| id.as_str().to_string());
+
+        if !status.is_server_error() {
+            warn!(code = self.code(), "{}", self);
+            return None;
+        }
+
+        let report = ErrorReport {
+            error_id: Uuid::new_v4().to_string(),
+            request_id,
+            status: status.as_u16(),
+            code: self.code(),
+            chain: self.chain(),
+            backtrace: self.backtrace(),
+            occurred_at: Utc::now(),
+        };
+        error!(
+            error_id = %report.error_id,
+            code = report.code,
+            "{}",
+            report.chain.join(": ")
+        );
+        if let Some(backtrace) = &report.backtrace {
+            error!(error_id = %report.error_id, "Backtrace:\n{}", backtrace);
+        }
+        error_reporter::report(&report);
+
+        Some(report.error_id)
+    }
+
+    fn headers(&self, error_id: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = error_id.and_then(|id| HeaderValue::from_str(id).ok()) {
+            headers.insert(ERROR_ID_HEADER, value);
+        }
+        match self {
+            AppError::Unauthorized(_) => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            AppError::TooManyRequests {
+                retry_after: Some(retry_after),
+            } => {
+                // Round up, so a client never retries before the limit resets.
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            }
+            _ => {}
+        }
+        headers
+    }
+
+    fn field_errors(&self) -> Option<Vec<FieldError>> {
+        match self {
+            AppError::ValidationFailed(errors) => Some(errors.clone()),
+            _ => None,
+        }
+    }
+
+    fn problem_details(&self, errors: &Errors, error_id: Option<&str>) -> ProblemDetails {
+        let status = self.status();
+        let request_id = RequestId::current().map(|id| id.as_str().to_string());
+
+        ProblemDetails {
+            problem_type: match &errors.type_base_url {
+                Some(base) => format!("{}/{}", base.trim_end_matches('/'), self.code()),
+                None => "about:blank".into(),
+            },
+            title: status.canonical_reason().unwrap_or("Error").into(),
+            status: status.as_u16(),
+            detail: self.public_message(error_id),
+            instance: request_id.as_ref().map(|id| format!("urn:request:{}", id)),
+            code: self.code().into(),
+            request_id,
+            error_id: error_id.map(str::to_string),
+            errors: self.field_errors(),
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        error!("Error: {}", self.to_string());
-        error!("Detailed error: {:#?}", self.source());
-
-        let (status, message) = match &self {
-            AppError::ServiceUnreachable(_) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
-            AppError::NotFound => (StatusCode::NOT_FOUND, self.to_string()),
-            AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::ForbiddenResource(_) => (StatusCode::FORBIDDEN, self.to_string()),
-            AppError::Other(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".into(),
-            ),
-        };
+        let error_id = self.log();
+        let error_id = error_id.as_deref();
 
-        (
-            status,
-            Json(StdResponse::<(), String> {
-                data: None,
-                message: Some(message),
-            }),
-        )
-            .into_response()
+        let errors = ERROR_RESPONSES
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let status = self.status();
+
+        match errors {
+            Some(errors) if errors.format == ErrorFormat::Problem => (
+                status,
+                self.headers(error_id),
+                [(header::CONTENT_TYPE, PROBLEM_JSON)],
+                Json(self.problem_details(&errors, error_id)),
+            )
+                .into_response(),
+            _ => (
+                status,
+                self.headers(error_id),
+                Json(StdResponse::<Vec<FieldError>, String> {
+                    data: self.field_errors(),
+                    message: Some(self.public_message(error_id)),
+                }),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Keeps the meaning of errors that passed through `anyhow`, e.g. from a helper such
+/// as `outbox::publish`: a wrapped `AppError` is unwrapped and a wrapped Diesel error
+/// is classified like a direct one, so a serialization failure stays retryable.
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<AppError>() {
+            Ok(app_error) => return app_error,
+            Err(err) => err,
+        };
+        let classified = err.downcast_ref::<DieselError>().and_then(classify_diesel);
+        classified.unwrap_or(AppError::Other(err))
     }
 }
 
 impl From<DieselError> for AppError {
     fn from(err: DieselError) -> Self {
-        match &err {
-            DieselError::DatabaseError(kind, _info) => match kind {
-                _ => AppError::Other(anyhow::Error::new(err)),
-            },
-            DieselError::NotFound => AppError::Other(anyhow::Error::new(err)),
-            _ => AppError::Other(anyhow::Error::new(err)),
-        }
+        classify_diesel(&err).unwrap_or_else(|| AppError::Other(anyhow::Error::new(err)))
     }
 }
+
+/// The `AppError` a Diesel error stands for, or `None` for a plain server error.
+fn classify_diesel(err: &DieselError) -> Option<AppError> {
+    let classified = match err {
+        DieselError::NotFound => AppError::NotFound,
+        DieselError::DatabaseError(kind, info) => {
+            let constraint = info.constraint_name().unwrap_or("unknown");
+            match kind {
+                DatabaseErrorKind::UniqueViolation => {
+                    AppError::Conflict(format!("Violates unique constraint {}", constraint))
+                }
+                DatabaseErrorKind::ForeignKeyViolation => AppError::UnprocessableEntity(format!(
+                    "Violates foreign key constraint {}",
+                    constraint
+                )),
+                DatabaseErrorKind::CheckViolation => AppError::UnprocessableEntity(format!(
+                    "Violates check constraint {}",
+                    constraint
+                )),
+                DatabaseErrorKind::NotNullViolation => AppError::UnprocessableEntity(format!(
+                    "Missing value for column {}",
+                    info.column_name().unwrap_or("unknown")
+                )),
+                DatabaseErrorKind::SerializationFailure => {
+                    AppError::Retryable("Serialization failure".into())
+                }
+                // Diesel has no kind for deadlocks (SQLSTATE 40P01) and does not expose
+                // the SQLSTATE, so the server message is matched instead. It is only in
+                // English when the server's `lc_messages` is `C` or an English locale;
+                // otherwise deadlocks surface as `Other` and are not retried.
+                DatabaseErrorKind::Unknown if info.message().contains("deadlock detected") => {
+                    AppError::Retryable("Deadlock detected".into())
+                }
+                DatabaseErrorKind::ClosedConnection => {
+                    AppError::ServiceUnreachable("database".into())
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(classified)
+}
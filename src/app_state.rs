@@ -1,25 +1,68 @@
+use std::{any::Any, sync::Arc};
+
 use anyhow::Result;
-use reqwest::Client;
+use diesel_async::{AsyncPgConnection, pooled_connection::bb8::PooledConnection};
 use rmq_wrappers::Rmq;
 
 use crate::{
-    config::DotEnvyConfig,
-    db::{self, DbPool},
+    app_error::AppError,
+    config::ServiceConfig,
+    config_watch::ConfigHandle,
+    db::{self, DbPool, ReplicaPool},
+    http_client::HttpClient,
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: DbPool,
-    pub http_client: Client,
+    /// Read replica from `DATABASE_REPLICA_URL`; use it through `read_conn`.
+    pub replica_pool: Option<ReplicaPool>,
+    pub http_client: HttpClient,
     pub rmq_client: Rmq,
+    pub config: ConfigHandle,
+    service_config: Arc<dyn Any + Send + Sync>,
 }
 
 impl AppState {
-    pub async fn init(config: &DotEnvyConfig) -> Result<Self> {
+    pub async fn init(config_handle: ConfigHandle) -> Result<Self> {
+        let config = config_handle.current();
         Ok(Self {
-            db_pool: db::connect(&config.database.url).await?,
-            http_client: Client::new(),
-            rmq_client: Rmq::connect(&config.message_queue.url).await?,
+            db_pool: db::connect(&config.database).await?,
+            replica_pool: db::connect_replica(&config.database).await?,
+            http_client: HttpClient::new(),
+            rmq_client: Rmq::connect(config.message_queue.url.expose()).await?,
+            config: config_handle,
+            service_config: Arc::new(()),
         })
     }
+
+    /// A connection for queries that only read, e.g. listing endpoints.
+    ///
+    /// Comes from the replica when one is configured, reachable and not lagging too
+    /// far behind, and from the primary otherwise, so it may see writes made on the
+    /// primary only after a delay. With a replica configured the connection is
+    /// read-only either way; without one it is a plain primary connection.
+    pub async fn read_conn(&self) -> Result<PooledConnection<'_, AsyncPgConnection>, AppError> {
+        let pool = match &self.replica_pool {
+            Some(replica) => match replica.get().await {
+                Some(conn) => return Ok(conn),
+                None => replica.fallback(),
+            },
+            None => &self.db_pool,
+        };
+        pool.get()
+            .await
+            .map_err(|_| AppError::ServiceUnreachable("database".into()))
+    }
+
+    pub fn with_service_config<S: ServiceConfig>(mut self, service_config: S) -> Self {
+        self.service_config = Arc::new(service_config);
+        self
+    }
+
+    /// The service's own config section, or `None` if `S` is not the type the
+    /// service was bootstrapped with.
+    pub fn service_config<S: ServiceConfig>(&self) -> Option<&S> {
+        self.service_config.downcast_ref::<S>()
+    }
 }
@@ -0,0 +1,163 @@
+use std::marker::PhantomData;
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+use crate::{
+    app_error::AppError,
+    jwt_authentication::{Claims, Roles},
+};
+
+/// Marker for a role a route can require through `Authenticated<R>`.
+pub trait Role: Send + Sync + 'static {
+    const ROLE: Roles;
+}
+
+#[derive(Debug, Clone)]
+pub struct PatientRole;
+
+impl Role for PatientRole {
+    const ROLE: Roles = Roles::Patient;
+}
+
+#[derive(Debug, Clone)]
+pub struct DoctorRole;
+
+impl Role for DoctorRole {
+    const ROLE: Roles = Roles::Doctor;
+}
+
+#[derive(Debug, Clone)]
+pub struct StaffRole;
+
+impl Role for StaffRole {
+    const ROLE: Roles = Roles::Staff;
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminRole;
+
+impl Role for AdminRole {
+    const ROLE: Roles = Roles::Admin;
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceRole;
+
+impl Role for ServiceRole {
+    const ROLE: Roles = Roles::Service;
+}
+
+/// Claims of a caller whose token was accepted by the authorization middleware
+/// and whose role is `R`.
+#[derive(Debug, Clone)]
+pub struct Authenticated<R: Role> {
+    pub claims: Claims,
+    _role: PhantomData<R>,
+}
+
+impl<R: Role> Authenticated<R> {
+    /// The token subject parsed as a numeric user id.
+    pub fn id(&self) -> Result<i32, AppError> {
+        self.claims
+            .sub
+            .parse()
+            .map_err(|_| AppError::Unauthorized("Token subject is not a user id".into()))
+    }
+
+    /// Fails unless the token grants `scope`, for checks that depend on the request
+    /// and so cannot be a `middleware::require_scope` layer.
+    pub fn require_scope(&self, scope: &str) -> Result<(), AppError> {
+        ensure_scope(&self.claims, scope)
+    }
+}
+
+pub(crate) fn ensure_scope(claims: &Claims, scope: &str) -> Result<(), AppError> {
+    match claims.has_scope(scope) {
+        true => Ok(()),
+        false => Err(AppError::ForbiddenResource(format!(
+            "Missing the {} scope",
+            scope
+        ))),
+    }
+}
+
+impl<S: Send + Sync, R: Role> FromRequestParts<S> for Authenticated<R> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts
+            .extensions
+            .get::<Claims>()
+            .ok_or_else(|| AppError::Unauthorized("Request has not been authenticated".into()))?;
+
+        if claims.role != R::ROLE {
+            return Err(AppError::ForbiddenResource(format!(
+                "Route requires the {:?} role",
+                R::ROLE
+            )));
+        }
+
+        Ok(Self {
+            claims: claims.clone(),
+            _role: PhantomData,
+        })
+    }
+}
+
+/// An authenticated patient, extracted from a route behind `patients_authorization`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedPatient {
+    pub id: i32,
+    pub claims: Claims,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedPatient {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let authenticated = Authenticated::<PatientRole>::from_request_parts(parts, state).await?;
+        Ok(Self {
+            id: authenticated.id()?,
+            claims: authenticated.claims,
+        })
+    }
+}
+
+/// An authenticated doctor, extracted from a route behind `doctors_authorization`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedDoctor {
+    pub id: i32,
+    pub claims: Claims,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedDoctor {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let authenticated = Authenticated::<DoctorRole>::from_request_parts(parts, state).await?;
+        Ok(Self {
+            id: authenticated.id()?,
+            claims: authenticated.claims,
+        })
+    }
+}
+
+/// Role and subject resolved by `middleware::authorization`, for routes that accept
+/// more than one role.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub role: Roles,
+    pub subject: String,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthenticatedUser>()
+            .cloned()
+            .ok_or_else(|| AppError::Unauthorized("Request has not been authenticated".into()))
+    }
+}
@@ -1,30 +1,101 @@
-use anyhow::Result;
-use axum::{Router, routing};
+use anyhow::{Context, Result};
+use axum::{
+    Extension, Router,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing,
+};
 use dotenvy::dotenv;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 use tokio::net::TcpListener;
-use tower_http::{limit::RequestBodyLimitLayer, timeout::TimeoutLayer, trace::TraceLayer};
-use tracing::info;
+use tower_http::{limit::RequestBodyLimitLayer, trace::TraceLayer};
+use tracing::{Span, info, info_span, warn};
+use tracing_subscriber::{
+    Registry, filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
-use crate::{app_state::AppState, config, consumers, cors, outbox};
+use crate::{
+    app_error,
+    app_state::AppState,
+    config::{self, ServiceConfig},
+    config_watch::ConfigHandle,
+    consumers, cors, db,
+    http_client::ServiceTokens,
+    jwt_authentication::TokenValidation,
+    middleware::{RoleKeys, SharedValidation},
+    outbox,
+    request_id::{self, RequestId},
+};
+
+static LOG_LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
 
 pub fn init_tracing() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+    let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer())
         .init();
+    LOG_LEVEL.set(handle).ok();
     info!("Initialized tracing");
 }
 
+/// Changes the log level set up by `init_tracing`. Does nothing if tracing was
+/// initialized some other way.
+pub fn set_log_level(level: LevelFilter) {
+    if let Some(handle) = LOG_LEVEL.get()
+        && let Err(e) = handle.modify(|current| *current = level)
+    {
+        warn!("Failed to change log level: {}", e);
+    }
+}
+
 pub fn init_env() {
     dotenv().ok();
     info!("Initialized .env");
 }
 
+async fn request_timeout(State(config): State<ConfigHandle>, req: Request, next: Next) -> Response {
+    let timeout = Duration::from_secs(config.current().server.timeout);
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => StatusCode::REQUEST_TIMEOUT.into_response(),
+    }
+}
+
+fn make_request_span(req: &Request) -> Span {
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(RequestId::to_string)
+        .unwrap_or_default();
+
+    info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id = %request_id,
+    )
+}
+
 /// Bootstraps a MedBook microservice with common setup steps.
 ///
 /// - Initializes tracing/logging
 /// - Loads .env
-/// - Creates shared AppState
+/// - Runs medbook-core's migrations (`db::CORE_MIGRATIONS`); the service's own
+///   migrations, which create `outbox`, must already be applied
+/// - Creates shared AppState, whose `HttpClient` signs calls to
+///   `INTERNAL_SERVICE_HOSTS` with a service token when `JWT_SERVICE_*` keys are
+///   configured
+/// - Makes auth policies validate access tokens against the issuer and
+///   `service_name` as audience, and check them for revocation
+/// - Assigns every request an `X-Request-Id`
+/// - Reloads CORS, log level, timeouts and token verification keys on SIGHUP or when
+///   the config files change
 /// - Starts RabbitMQ consumers
 /// - Spawns the outbox worker
 /// - Runs the Axum server
@@ -33,15 +104,58 @@ pub async fn bootstrap(
     app: Router<AppState>,
     queue_handlers: &[(&str, consumers::ConsumerFn)],
 ) -> Result<()> {
-    let config = config::load()?;
+    bootstrap_with::<()>(service_name, app, queue_handlers).await
+}
+
+/// Same as `bootstrap`, but also loads the service's own config section `S`
+/// and makes it available through `AppState::service_config`.
+pub async fn bootstrap_with<S: ServiceConfig>(
+    service_name: &str,
+    app: Router<AppState>,
+    queue_handlers: &[(&str, consumers::ConsumerFn)],
+) -> Result<()> {
+    let (config, service_config) = config::load_with::<S>()?;
     info!("Config loaded");
 
+    let config_handle = ConfigHandle::new(config.clone());
+    config_handle.on_change(|config| set_log_level(config.logging.level));
+    config_handle.on_change(|config| app_error::set_error_responses(config.errors.clone()));
+    let token_validation =
+        SharedValidation::new(TokenValidation::for_service(&config.jwt, service_name));
+    let audience = service_name.to_string();
+    let validation = token_validation.clone();
+    config_handle.on_change(move |config| {
+        validation.set(TokenValidation::for_service(&config.jwt, &audience))
+    });
+    let role_keys = RoleKeys::default();
+    let keys = role_keys.clone();
+    config_handle.on_change(move |_| {
+        if let Err(e) = keys.reload() {
+            warn!("Keeping the current verification keys: {:#}", e);
+        }
+    });
+    config_handle.watch();
+
     let port = config.server.port;
     let ip = format!("0.0.0.0:{}", port);
     info!("Starting {} on {}...", service_name, ip);
 
+    let applied = db::run_migrations_blocking(db::CORE_MIGRATIONS, config.database.url.expose())
+        .await
+        .context(
+            "Failed to run medbook-core migrations, make sure the service's own migrations \
+             (which create the outbox table) ran first",
+        )?;
+    info!("Applied {} medbook-core migrations", applied);
+
     // Shared app state
-    let app_state = AppState::init(&config).await?;
+    let mut app_state = AppState::init(config_handle.clone())
+        .await?
+        .with_service_config(service_config);
+    if let Some(service_tokens) = ServiceTokens::from_config(service_name, &config.jwt)? {
+        app_state.http_client = app_state.http_client.with_service_tokens(service_tokens);
+        info!("Outgoing calls carry a service token");
+    }
     let shared_state = Arc::new(app_state.clone());
 
     // Start all message consumers
@@ -56,17 +170,26 @@ pub async fn bootstrap(
     let app = app
         .route("/health-check", routing::get(|| async { "OK" }))
         .with_state(app_state)
-        .layer(TimeoutLayer::new(Duration::from_secs(
-            config.server.timeout,
-        )))
+        .layer(middleware::from_fn_with_state(
+            config_handle.clone(),
+            request_timeout,
+        ))
         .layer(RequestBodyLimitLayer::new(config.server.body_limit))
-        .layer(TraceLayer::new_for_http())
-        .layer(cors::create_from_stage(config::get_stage(), &config));
+        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+        .layer(Extension(token_validation))
+        .layer(Extension(role_keys))
+        .layer(Extension(shared_state.db_pool.clone()))
+        .layer(middleware::from_fn(request_id::request_id))
+        .layer(middleware::from_fn_with_state(
+            config_handle.derive(|config| cors::create_from_stage(config.stage.clone(), config)),
+            cors::reloadable,
+        ));
 
     info!("Initialized TimeoutLayer");
     info!("Initialized RequestBodyLimitLayer");
     info!("Initialized TraceLayer");
-    info!("Initialized CORS with stage: {}", config::get_stage());
+    info!("Initialized RequestIdLayer");
+    info!("Initialized CORS with stage: {}", config.stage);
 
     // Start outbox worker
     outbox::init(shared_state.clone());
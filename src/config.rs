@@ -1,4 +1,16 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
 use anyhow::Result;
+use jsonwebtoken::Algorithm;
+use reqwest::Url;
+use serde_json::Value;
+use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
 
 #[derive(Debug, Clone)]
 pub struct DotEnvyConfig {
@@ -6,6 +18,11 @@ pub struct DotEnvyConfig {
     pub frontend: Frontend,
     pub database: Database,
     pub message_queue: MessageQueue,
+    pub logging: Logging,
+    pub jwt: Jwt,
+    pub errors: Errors,
+    pub pagination: Pagination,
+    pub stage: Stage,
 }
 
 #[derive(Debug, Clone)]
@@ -23,28 +40,376 @@ pub struct Frontend {
 }
 
 #[derive(Debug, Clone)]
+pub struct Logging {
+    pub level: LevelFilter,
+}
+
+#[derive(Debug, Clone)]
+pub struct Jwt {
+    /// Access token lifetime in seconds.
+    pub access_ttl: u64,
+    /// Refresh token lifetime in seconds.
+    pub refresh_ttl: u64,
+    /// `iss` of issued tokens; verifying services only accept this issuer.
+    pub issuer: String,
+    /// Services issued access tokens are meant for (`aud`).
+    pub audience: Vec<String>,
+    /// Clock skew in seconds tolerated when checking `exp` and `nbf`.
+    pub leeway: u64,
+    /// Lifetime in seconds of the tokens this service attaches to its own calls.
+    pub service_token_ttl: u64,
+    /// Scopes requested in the tokens this service attaches to its own calls.
+    pub service_scopes: Vec<String>,
+    /// `aud` of the tokens this service attaches to its own calls: the services it calls.
+    pub service_audience: Vec<String>,
+    /// Hosts, optionally with a port, whose calls carry a service token. Calls to any
+    /// other host never do.
+    pub internal_hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Errors {
+    pub format: ErrorFormat,
+    /// Prefix of the problem `type` URI, followed by the error code. Without it the
+    /// type is `about:blank`.
+    pub type_base_url: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    pub default_page_size: i64,
+    /// Largest `page_size` a client may ask for.
+    pub max_page_size: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Database {
-    pub url: String,
+    pub url: Secret,
+    /// Most connections the pool keeps open.
+    pub max_connections: u32,
+    /// Idle connections the pool tries to keep ready; `None` lets it drain to zero.
+    pub min_idle: Option<u32>,
+    /// Seconds an idle connection is kept before it is closed, 0 for no limit.
+    pub idle_timeout: u64,
+    /// Seconds after which a connection is replaced, 0 for no limit.
+    pub max_lifetime: u64,
+    /// Milliseconds to wait for a connection before the checkout fails.
+    pub connection_timeout: u64,
+    /// Milliseconds a statement may run before Postgres cancels it.
+    pub statement_timeout: Option<u64>,
+    /// Run a test query on every checkout, so a dead connection is never handed out.
+    pub test_on_checkout: bool,
+    /// Milliseconds of waiting for a connection after which a warning is logged.
+    pub slow_acquire_threshold: u64,
+    /// Read replica used by `AppState::read_conn`. Its pool uses the same settings
+    /// except for its size.
+    pub replica_url: Option<Secret>,
+    /// Most connections the replica pool keeps open, and the read-only pool on the
+    /// primary that serves reads while the replica is unusable.
+    pub replica_max_connections: u32,
+    /// Seconds the replica may lag behind the primary before reads go to the primary.
+    pub replica_max_lag: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct MessageQueue {
-    pub url: String,
+    pub url: Secret,
+}
+
+impl DotEnvyConfig {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            server: Server::from_source(source),
+            frontend: Frontend::from_source(source),
+            database: Database::from_source(source),
+            message_queue: MessageQueue::from_source(source),
+            logging: Logging::from_source(source),
+            jwt: Jwt::from_source(source),
+            errors: Errors::from_source(source),
+            pagination: Pagination::from_source(source),
+            stage: source.with_default("STAGE", Stage::default()),
+        }
+    }
+}
+
+impl Server {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        let port = source.required_with("SERVER_PORT", |port| match port {
+            0 => Err("port must be between 1 and 65535".into()),
+            _ => Ok(()),
+        });
+
+        Self {
+            port,
+            body_limit: source.with_default("SERVER_BODY_LIMIT", 10 * 1024 * 1024),
+            timeout: source.with_default("SERVER_TIMEOUT", 30),
+            path_prefix: source.with_default("PATH_PREFIX", "/".to_string()),
+        }
+    }
+}
+
+impl Frontend {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            production_url: source.required_url("PRODUCTION_FRONTEND_URL"),
+            development_url: source.required_url("DEVELOPMENT_FRONTEND_URL"),
+        }
+    }
+}
+
+impl Logging {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            level: source.with_default("LOG_LEVEL", LevelFilter::INFO),
+        }
+    }
+}
+
+impl Jwt {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            access_ttl: source.with_default("JWT_ACCESS_TTL", 15 * 60),
+            refresh_ttl: source.with_default("JWT_REFRESH_TTL", 7 * 24 * 60 * 60),
+            issuer: source.with_default("JWT_ISSUER", "medbook".to_string()),
+            audience: source.list("JWT_AUDIENCE"),
+            leeway: source.with_default("JWT_LEEWAY", 30),
+            service_token_ttl: source.with_default("JWT_SERVICE_TOKEN_TTL", 60),
+            service_scopes: source.list("JWT_SERVICE_SCOPES"),
+            service_audience: source.list("SERVICE_AUDIENCE"),
+            internal_hosts: source.list("INTERNAL_SERVICE_HOSTS"),
+        }
+    }
+}
+
+impl Errors {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            format: source.with_default("ERROR_FORMAT", ErrorFormat::default()),
+            type_base_url: source.optional("ERROR_TYPE_BASE_URL"),
+        }
+    }
+}
+
+impl Pagination {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        let default_page_size = source.with_default("PAGE_SIZE_DEFAULT", 20);
+        let max_page_size = source.with_default("PAGE_SIZE_MAX", 100);
+        source.check("PAGE_SIZE_MAX", || match max_page_size {
+            max if max < 1 => Err("must be at least 1".into()),
+            max if max < default_page_size => {
+                Err("must not be smaller than PAGE_SIZE_DEFAULT".into())
+            }
+            _ => Ok(()),
+        });
+        source.check("PAGE_SIZE_DEFAULT", || match default_page_size {
+            1.. => Ok(()),
+            _ => Err("must be at least 1".into()),
+        });
+
+        Self {
+            default_page_size,
+            max_page_size,
+        }
+    }
+}
+
+impl Database {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        let max_connections = source.with_default("DATABASE_POOL_MAX_SIZE", 10);
+        let min_idle = source.optional("DATABASE_POOL_MIN_IDLE");
+        source.check("DATABASE_POOL_MAX_SIZE", || match max_connections {
+            0 => Err("must be at least 1".into()),
+            _ => Ok(()),
+        });
+        source.check("DATABASE_POOL_MIN_IDLE", || match min_idle {
+            Some(min) if min > max_connections => {
+                Err("must not be larger than DATABASE_POOL_MAX_SIZE".into())
+            }
+            _ => Ok(()),
+        });
+
+        let replica_max_connections =
+            source.with_default("DATABASE_REPLICA_POOL_MAX_SIZE", max_connections);
+        source.check(
+            "DATABASE_REPLICA_POOL_MAX_SIZE",
+            || match replica_max_connections {
+                0 => Err("must be at least 1".into()),
+                _ => Ok(()),
+            },
+        );
+
+        let connection_timeout = source.with_default("DATABASE_POOL_CONNECTION_TIMEOUT_MS", 1000);
+        source.check(
+            "DATABASE_POOL_CONNECTION_TIMEOUT_MS",
+            || match connection_timeout {
+                0 => Err("must be at least 1".into()),
+                _ => Ok(()),
+            },
+        );
+
+        Self {
+            url: source.required_url("DATABASE_URL"),
+            max_connections,
+            min_idle,
+            idle_timeout: source.with_default("DATABASE_POOL_IDLE_TIMEOUT", 10 * 60),
+            max_lifetime: source.with_default("DATABASE_POOL_MAX_LIFETIME", 30 * 60),
+            connection_timeout,
+            statement_timeout: source.optional("DATABASE_STATEMENT_TIMEOUT_MS"),
+            test_on_checkout: source.with_default("DATABASE_POOL_TEST_ON_CHECKOUT", true),
+            slow_acquire_threshold: source.with_default("DATABASE_POOL_SLOW_ACQUIRE_MS", 200),
+            replica_url: source.optional_url("DATABASE_REPLICA_URL"),
+            replica_max_connections,
+            replica_max_lag: source.with_default("DATABASE_REPLICA_MAX_LAG", 10),
+        }
+    }
+}
+
+impl MessageQueue {
+    pub fn from_source(source: &mut ConfigSource) -> Self {
+        Self {
+            url: source.required_url("RMQ_URL"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PatientsSecret {
-    pub secret: String,
-    pub refresh_secret: String,
+    pub secret: Secret,
+    pub refresh_secret: Secret,
 }
 
 #[derive(Debug, Clone)]
 pub struct DoctorsSecret {
-    pub secret: String,
-    pub refresh_secret: String,
+    pub secret: Secret,
+    pub refresh_secret: Secret,
+}
+
+/// Access token keys of one role, read from `{prefix}_*` (e.g. `JWT_PATIENT_*`).
+///
+/// HMAC algorithms use `{prefix}_SECRET`. Asymmetric ones (RS256, ES256, EdDSA, ...)
+/// use PEM keys: `{prefix}_PUBLIC_KEY` is needed to verify, `{prefix}_PRIVATE_KEY`
+/// only by the service that issues tokens. Keys being rotated out stay valid for
+/// verification through `{prefix}_RETIRED_KEYS`, a comma-separated `kid=path` list.
+#[derive(Debug, Clone)]
+pub struct JwtKeys {
+    pub algorithm: Algorithm,
+    pub key_id: Option<String>,
+    pub secret: Option<Secret>,
+    pub private_key: Option<Secret>,
+    pub public_key: Option<String>,
+    pub retired_keys: Vec<RetiredKey>,
+}
+
+/// A public key that no longer signs tokens but still verifies them.
+#[derive(Debug, Clone)]
+pub struct RetiredKey {
+    pub key_id: String,
+    pub public_key: String,
+}
+
+impl JwtKeys {
+    pub fn from_source(source: &mut ConfigSource, prefix: &str) -> Self {
+        let key = |name: &str| format!("{}_{}", prefix, name);
+
+        let algorithm = source.with_default(&key("ALGORITHM"), Algorithm::HS256);
+        let is_hmac = matches!(
+            algorithm,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        );
+
+        let (secret, public_key) = match is_hmac {
+            true => (Some(source.required(&key("SECRET"))), None),
+            false => (None, Some(source.required(&key("PUBLIC_KEY")))),
+        };
+
+        let retired_keys = source
+            .optional::<String>(&key("RETIRED_KEYS"))
+            .map(|list| parse_retired_keys(source, &key("RETIRED_KEYS"), &list))
+            .unwrap_or_default();
+
+        Self {
+            algorithm,
+            key_id: source.optional(&key("KEY_ID")),
+            secret,
+            private_key: source.optional(&key("PRIVATE_KEY")),
+            public_key,
+            retired_keys,
+        }
+    }
+}
+
+fn parse_retired_keys(source: &mut ConfigSource, key: &str, list: &str) -> Vec<RetiredKey> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let Some((key_id, path)) = entry.split_once('=') else {
+                source.invalid(key, format!("expected kid=path, got {}", entry));
+                return None;
+            };
+            match fs::read_to_string(path.trim()) {
+                Ok(public_key) => Some(RetiredKey {
+                    key_id: key_id.trim().to_string(),
+                    public_key,
+                }),
+                Err(e) => {
+                    source.invalid(key, format!("{} could not be read: {}", path.trim(), e));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// A sensitive config value. Its `Debug` output is redacted, so configs holding it
+/// can be logged safely; call `expose` where the actual value is needed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(value))
+    }
+}
+
+/// Body of error responses: the `StdResponse` envelope, or RFC 7807
+/// `application/problem+json`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ErrorFormat {
+    #[default]
+    Standard,
+    Problem,
 }
 
-use std::fmt;
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_ascii_lowercase().as_str() {
+            "standard" => Ok(ErrorFormat::Standard),
+            "problem" => Ok(ErrorFormat::Problem),
+            _ => Err("expected standard or problem".into()),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub enum Stage {
@@ -77,68 +442,568 @@ impl Stage {
     }
 }
 
-pub fn load() -> Result<DotEnvyConfig> {
-    dotenvy::dotenv().ok();
+impl FromStr for Stage {
+    type Err = anyhow::Error;
 
-    let server = Server {
-        port: std::env::var("SERVER_PORT")
-            .expect("SERVER_PORT is invalid")
-            .parse()?,
-        body_limit: std::env::var("SERVER_BODY_LIMIT")
-            .expect("SERVER_BODY_LIMIT is invalid")
-            .parse()?,
-        timeout: std::env::var("SERVER_TIMEOUT")
-            .expect("SERVER_TIMEOUT is invalid")
-            .parse()?,
-        path_prefix: std::env::var("PATH_PREFIX").expect("PATH_PREFIX is invalid"),
-    };
+    fn from_str(stage: &str) -> Result<Self> {
+        Stage::try_from(stage)
+    }
+}
 
-    let frontend = Frontend {
-        production_url: std::env::var("PRODUCTION_FRONTEND_URL")
-            .expect("PRODUCTION_FRONTEND_URL is invalid"),
-        development_url: std::env::var("DEVELOPMENT_FRONTEND_URL")
-            .expect("DEVELOPMENT_FRONTEND_URL is invalid"),
-    };
+/// Loads the service configuration.
+///
+/// Values are read from the environment (after loading `.env`) and, when `CONFIG_FILE`
+/// points to a TOML or YAML file, from that file. Environment variables take precedence.
+/// Every missing or invalid key is reported in a single `ConfigError`.
+pub fn load() -> Result<DotEnvyConfig, ConfigError> {
+    load_with::<()>().map(|(config, _)| config)
+}
 
-    let database = Database {
-        url: std::env::var("DATABASE_URL").expect("DATABASE_URL is invalid"),
-    };
+/// Like `load`, but also loads the service's own section `S` in the same pass,
+/// so its problems are reported together with the core ones.
+pub fn load_with<S: ServiceConfig>() -> Result<(DotEnvyConfig, S), ConfigError> {
+    let mut source = ConfigSource::from_env();
+    let config = DotEnvyConfig::from_source(&mut source);
+    let service = S::from_source(&mut source);
+    source.finish((config, service))
+}
 
-    let message_queue = MessageQueue {
-        url: std::env::var("RMQ_URL").expect("RMQ_URL is invalid"),
-    };
+/// Settings specific to one service (upstream URLs, feature flags, ...).
+///
+/// Implement it for a struct and pass it to `bootstrap::bootstrap_with`; the loaded
+/// value is then available through `AppState::service_config`.
+pub trait ServiceConfig: Sized + Send + Sync + 'static {
+    fn from_source(source: &mut ConfigSource) -> Self;
+}
 
-    Ok(DotEnvyConfig {
-        server,
-        frontend,
-        database,
-        message_queue,
-    })
+impl ServiceConfig for () {
+    fn from_source(_source: &mut ConfigSource) -> Self {}
 }
 
 pub fn get_stage() -> Stage {
-    dotenvy::dotenv().ok();
+    ConfigSource::from_env()
+        .optional("STAGE")
+        .unwrap_or_default()
+}
 
-    let stage_str = std::env::var("STAGE").unwrap_or("".to_string());
-    Stage::try_from(&stage_str).unwrap_or_default()
+/// The `.env` file in the working directory or its closest parent, looked up the way
+/// `dotenvy::dotenv` does, but without loading it.
+pub fn dotenv_path() -> Option<PathBuf> {
+    let mut dir = std::env::current_dir().ok()?;
+    loop {
+        let candidate = dir.join(".env");
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        if !dir.pop() {
+            return None;
+        }
+    }
 }
 
 pub fn get_patients_secret_env() -> Result<PatientsSecret> {
-    dotenvy::dotenv().ok();
-
-    Ok(PatientsSecret {
-        secret: std::env::var("JWT_PATIENT_SECRET").expect("JWT_PATIENT_SECRET is invalid"),
-        refresh_secret: std::env::var("JWT_PATIENT_REFRESH_SECRET")
-            .expect("JWT_PATIENT_REFRESH_SECRET is invalid"),
-    })
+    let mut source = ConfigSource::from_env();
+    let secret = PatientsSecret {
+        secret: source.required("JWT_PATIENT_SECRET"),
+        refresh_secret: source.required("JWT_PATIENT_REFRESH_SECRET"),
+    };
+    Ok(source.finish(secret)?)
 }
 
 pub fn get_doctors_secret_env() -> Result<DoctorsSecret> {
-    dotenvy::dotenv().ok();
+    let mut source = ConfigSource::from_env();
+    let secret = DoctorsSecret {
+        secret: source.required("JWT_DOCTOR_SECRET"),
+        refresh_secret: source.required("JWT_DOCTOR_REFRESH_SECRET"),
+    };
+    Ok(source.finish(secret)?)
+}
+
+/// Loads the `JWT_*` token settings.
+pub fn get_jwt_env() -> Result<Jwt> {
+    let mut source = ConfigSource::from_env();
+    let jwt = Jwt::from_source(&mut source);
+    Ok(source.finish(jwt)?)
+}
+
+/// Loads `{prefix}_REFRESH_SECRET`, the secret refresh tokens are signed with.
+pub fn get_refresh_secret_env(prefix: &str) -> Result<Secret> {
+    let mut source = ConfigSource::from_env();
+    let secret = source.required(&format!("{}_REFRESH_SECRET", prefix));
+    Ok(source.finish(secret)?)
+}
+
+/// Loads the access token keys for a role prefix such as `JWT_PATIENT`.
+pub fn get_jwt_keys_env(prefix: &str) -> Result<JwtKeys> {
+    let mut source = ConfigSource::from_env();
+    let keys = JwtKeys::from_source(&mut source, prefix);
+    Ok(source.finish(keys)?)
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigIssue {
+    #[error("{0} (or {0}_FILE) is missing")]
+    Missing(String),
+
+    #[error("{key} is invalid: {reason}")]
+    Invalid { key: String, reason: String },
+
+    #[error("config file {path} could not be read: {reason}")]
+    File { path: String, reason: String },
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration ({} problems)", self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Key/value view over `.env`, the process environment and an optional config file.
+///
+/// Readers record problems instead of failing, so a single `finish` call can report
+/// every missing or invalid key at once.
+#[derive(Default)]
+pub struct ConfigSource {
+    values: HashMap<String, String>,
+    issues: Vec<ConfigIssue>,
+}
+
+impl ConfigSource {
+    /// Reads `CONFIG_FILE` if set, then `.env`, then the process environment, each
+    /// overriding the one before. Problems with either file are reported by `finish`.
+    ///
+    /// `.env` is parsed into the source rather than loaded into the environment, so
+    /// this is safe to call while other threads read the environment.
+    pub fn from_env() -> Self {
+        let mut source = Self::default();
+        if let Ok(path) = std::env::var("CONFIG_FILE") {
+            source.merge_file(Path::new(&path));
+        }
+        if let Some(path) = dotenv_path() {
+            source.merge_dotenv(&path);
+        }
+        source.values.extend(std::env::vars());
+        source
+    }
+
+    /// Builds a source from explicit values, e.g. for tests or tooling.
+    pub fn from_values<I, K, V>(values: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Self {
+            values: values
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            issues: Vec::new(),
+        }
+    }
+
+    fn merge_dotenv(&mut self, path: &Path) {
+        let entries = dotenvy::from_path_iter(path)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>());
+        match entries {
+            Ok(entries) => self.values.extend(entries),
+            Err(e) => self.issues.push(ConfigIssue::File {
+                path: path.display().to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    /// Merges a TOML or YAML file. Sections and keys follow the `DotEnvyConfig` field
+    /// names and are flattened, so `[server] port = 80` is read as `SERVER_PORT`;
+    /// keys whose env name does not follow that pattern are mapped through
+    /// `FILE_KEY_ALIASES`, e.g. `[message_queue] url` to `RMQ_URL`.
+    fn merge_file(&mut self, path: &Path) {
+        let file_issue = |reason: String| ConfigIssue::File {
+            path: path.display().to_string(),
+            reason,
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return self.issues.push(file_issue(e.to_string())),
+        };
+
+        let parsed: Result<Value, String> = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            _ => Err("unsupported file extension, expected .toml, .yaml or .yml".into()),
+        };
+
+        match parsed {
+            Ok(value) => {
+                let mut file_values = HashMap::new();
+                flatten_into(&mut file_values, String::new(), value);
+                self.values
+                    .extend(file_values.into_iter().map(|(key, value)| {
+                        let key = FILE_KEY_ALIASES
+                            .iter()
+                            .find(|(file_key, _)| *file_key == key)
+                            .map_or(key, |(_, env_key)| env_key.to_string());
+                        (key, value)
+                    }));
+            }
+            Err(reason) => self.issues.push(file_issue(reason)),
+        }
+    }
+
+    /// Looks up `key`, falling back to the contents of the file named by `{key}_FILE`
+    /// (the Docker/Kubernetes secrets convention).
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        if let Some(value) = self.values.get(key).filter(|value| !value.is_empty()) {
+            return Some(value.clone());
+        }
+
+        let file_key = format!("{}_FILE", key);
+        let path = self
+            .values
+            .get(&file_key)
+            .filter(|path| !path.is_empty())?
+            .clone();
+        match fs::read_to_string(&path) {
+            Ok(content) => Some(content.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => {
+                self.invalid(&file_key, format!("{} could not be read: {}", path, e));
+                None
+            }
+        }
+    }
+
+    /// Reads a required value. Returns `T::default()` and records an issue when it is
+    /// missing or cannot be parsed.
+    pub fn required<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        match self.get(key) {
+            Some(raw) => self.parse(key, &raw).unwrap_or_default(),
+            None => {
+                self.missing(key);
+                T::default()
+            }
+        }
+    }
+
+    /// Like `required`, but also runs `rule` on the value. The rule is skipped when the
+    /// value is missing or cannot be parsed, since that is already reported.
+    pub fn required_with<T>(&mut self, key: &str, rule: impl FnOnce(&T) -> Result<(), String>) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        let Some(raw) = self.get(key) else {
+            self.missing(key);
+            return T::default();
+        };
+        let Some(value) = self.parse(key, &raw) else {
+            return T::default();
+        };
+        self.check(key, || rule(&value));
+        value
+    }
+
+    /// Reads an optional value. Records an issue when it is present but cannot be parsed.
+    pub fn optional<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let raw = self.get(key)?;
+        self.parse(key, &raw)
+    }
 
-    Ok(DoctorsSecret {
-        secret: std::env::var("JWT_DOCTOR_SECRET").expect("JWT_DOCTOR_SECRET is invalid"),
-        refresh_secret: std::env::var("JWT_DOCTOR_REFRESH_SECRET")
-            .expect("JWT_DOCTOR_REFRESH_SECRET is invalid"),
-    })
+    pub fn with_default<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.optional(key).unwrap_or(default)
+    }
+
+    /// Reads a comma-separated list. Missing keys give an empty list.
+    pub fn list(&mut self, key: &str) -> Vec<String> {
+        self.get(key)
+            .map(|raw| {
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Reads a required value that must be an absolute URL.
+    pub fn required_url<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        let Some(raw) = self.get(key) else {
+            self.missing(key);
+            return T::default();
+        };
+        // The parse error never contains the URL itself, so credentials are not leaked.
+        self.check(key, || {
+            Url::parse(&raw).map(|_| ()).map_err(|e| e.to_string())
+        });
+        self.parse(key, &raw).unwrap_or_default()
+    }
+
+    /// Like `required_url`, but a missing value gives `None`.
+    pub fn optional_url<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let raw = self.get(key)?;
+        self.check(key, || {
+            Url::parse(&raw).map(|_| ()).map_err(|e| e.to_string())
+        });
+        self.parse(key, &raw)
+    }
+
+    fn parse<T>(&mut self, key: &str, raw: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match raw.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.invalid(key, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn missing(&mut self, key: &str) {
+        self.issues.push(ConfigIssue::Missing(key.to_string()));
+    }
+
+    /// Runs a validation rule and records its error against `key`.
+    pub fn check(&mut self, key: &str, rule: impl FnOnce() -> Result<(), String>) {
+        if let Err(reason) = rule() {
+            self.invalid(key, reason);
+        }
+    }
+
+    pub fn invalid(&mut self, key: &str, reason: impl Into<String>) {
+        self.issues.push(ConfigIssue::Invalid {
+            key: key.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Returns `config` if nothing was reported, or every recorded issue otherwise.
+    pub fn finish<T>(self, config: T) -> Result<T, ConfigError> {
+        match self.issues.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError {
+                issues: self.issues,
+            }),
+        }
+    }
+}
+
+/// Flattened config file keys whose env name is not `SECTION_KEY`.
+const FILE_KEY_ALIASES: &[(&str, &str)] = &[
+    ("FRONTEND_PRODUCTION_URL", "PRODUCTION_FRONTEND_URL"),
+    ("FRONTEND_DEVELOPMENT_URL", "DEVELOPMENT_FRONTEND_URL"),
+    ("MESSAGE_QUEUE_URL", "RMQ_URL"),
+    ("SERVER_PATH_PREFIX", "PATH_PREFIX"),
+    ("LOGGING_LEVEL", "LOG_LEVEL"),
+    ("ERRORS_FORMAT", "ERROR_FORMAT"),
+    ("ERRORS_TYPE_BASE_URL", "ERROR_TYPE_BASE_URL"),
+    ("PAGINATION_DEFAULT_PAGE_SIZE", "PAGE_SIZE_DEFAULT"),
+    ("PAGINATION_MAX_PAGE_SIZE", "PAGE_SIZE_MAX"),
+    ("JWT_SERVICE_AUDIENCE", "SERVICE_AUDIENCE"),
+    ("JWT_INTERNAL_HOSTS", "INTERNAL_SERVICE_HOSTS"),
+    ("DATABASE_MAX_CONNECTIONS", "DATABASE_POOL_MAX_SIZE"),
+    ("DATABASE_MIN_IDLE", "DATABASE_POOL_MIN_IDLE"),
+    ("DATABASE_IDLE_TIMEOUT", "DATABASE_POOL_IDLE_TIMEOUT"),
+    ("DATABASE_MAX_LIFETIME", "DATABASE_POOL_MAX_LIFETIME"),
+    (
+        "DATABASE_CONNECTION_TIMEOUT",
+        "DATABASE_POOL_CONNECTION_TIMEOUT_MS",
+    ),
+    (
+        "DATABASE_STATEMENT_TIMEOUT",
+        "DATABASE_STATEMENT_TIMEOUT_MS",
+    ),
+    (
+        "DATABASE_TEST_ON_CHECKOUT",
+        "DATABASE_POOL_TEST_ON_CHECKOUT",
+    ),
+    (
+        "DATABASE_SLOW_ACQUIRE_THRESHOLD",
+        "DATABASE_POOL_SLOW_ACQUIRE_MS",
+    ),
+    (
+        "DATABASE_REPLICA_MAX_CONNECTIONS",
+        "DATABASE_REPLICA_POOL_MAX_SIZE",
+    ),
+];
+
+fn flatten_into(values: &mut HashMap<String, String>, prefix: String, value: Value) {
+    let join = |key: &str| match prefix.is_empty() {
+        true => key.to_uppercase(),
+        false => format!("{}_{}", prefix, key.to_uppercase()),
+    };
+
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_into(values, join(&key), value);
+            }
+        }
+        Value::Array(items) => {
+            let joined = items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            values.insert(prefix, joined);
+        }
+        Value::String(s) => {
+            values.insert(prefix, s);
+        }
+        Value::Null => {}
+        other => {
+            values.insert(prefix, other.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn issue_keys(error: ConfigError) -> Vec<String> {
+        error
+            .issues
+            .iter()
+            .map(|issue| match issue {
+                ConfigIssue::Missing(key) => format!("missing {}", key),
+                ConfigIssue::Invalid { key, .. } => format!("invalid {}", key),
+                ConfigIssue::File { path, .. } => format!("file {}", path),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_issue_is_reported_at_once() {
+        let mut source = ConfigSource::from_values([
+            ("SERVER_PORT", "http"),
+            ("DATABASE_URL", "postgres://localhost/medbook"),
+            ("PAGE_SIZE_DEFAULT", "0"),
+        ]);
+        let config = DotEnvyConfig::from_source(&mut source);
+        let issues = issue_keys(source.finish(config).unwrap_err());
+
+        for expected in [
+            "invalid SERVER_PORT",
+            "missing PRODUCTION_FRONTEND_URL",
+            "missing DEVELOPMENT_FRONTEND_URL",
+            "missing RMQ_URL",
+            "invalid PAGE_SIZE_DEFAULT",
+        ] {
+            assert!(issues.iter().any(|issue| issue == expected), "{:?}", issues);
+        }
+        assert!(!issues.iter().any(|issue| issue.ends_with("DATABASE_URL")));
+    }
+
+    #[test]
+    fn missing_port_is_not_also_out_of_range() {
+        let mut source = ConfigSource::from_values::<_, String, String>([]);
+        let server = Server::from_source(&mut source);
+        assert_eq!(
+            issue_keys(source.finish(server).unwrap_err()),
+            ["missing SERVER_PORT"]
+        );
+
+        let mut source = ConfigSource::from_values([("SERVER_PORT", "0")]);
+        let server = Server::from_source(&mut source);
+        assert_eq!(
+            issue_keys(source.finish(server).unwrap_err()),
+            ["invalid SERVER_PORT"]
+        );
+    }
+
+    #[test]
+    fn file_keys_are_flattened_and_aliased() {
+        let path = temp_file(
+            "medbook.toml",
+            r#"
+            [server]
+            port = 8080
+
+            [message_queue]
+            url = "amqp://localhost:5672"
+
+            [jwt]
+            audience = ["appointments", "records"]
+
+            [database]
+            max_connections = 5
+            "#,
+        );
+        let mut source = ConfigSource::default();
+        source.merge_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(source.get("SERVER_PORT").as_deref(), Some("8080"));
+        assert_eq!(
+            source.get("RMQ_URL").as_deref(),
+            Some("amqp://localhost:5672")
+        );
+        assert_eq!(source.get("MESSAGE_QUEUE_URL"), None);
+        assert_eq!(source.list("JWT_AUDIENCE"), ["appointments", "records"]);
+        assert_eq!(source.get("DATABASE_POOL_MAX_SIZE").as_deref(), Some("5"));
+        assert!(source.finish(()).is_ok());
+    }
+
+    #[test]
+    fn values_fall_back_to_file_contents() {
+        let path = temp_file("database-url", "postgres://localhost/medbook\n");
+        let mut source = ConfigSource::from_values([("DATABASE_URL_FILE", path.to_str().unwrap())]);
+        let url = source.get("DATABASE_URL");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(url.as_deref(), Some("postgres://localhost/medbook"));
+
+        let mut source = ConfigSource::from_values([("DATABASE_URL_FILE", "/nonexistent/url")]);
+        assert_eq!(source.get("DATABASE_URL"), None);
+        assert_eq!(
+            issue_keys(source.finish(()).unwrap_err()),
+            ["invalid DATABASE_URL_FILE"]
+        );
+    }
 }
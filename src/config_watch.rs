@@ -0,0 +1,200 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::{self, ConfigError, DotEnvyConfig, Server};
+
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shared, reloadable view of the service configuration.
+///
+/// Only non-structural settings (CORS origins, stage, log level, request timeout,
+/// token lifetimes, error format, page sizes) change on reload. Ports, body limits,
+/// database and queue URLs keep their startup values and only a restart picks up
+/// new ones.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    sender: Arc<watch::Sender<Arc<DotEnvyConfig>>>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: DotEnvyConfig) -> Self {
+        let (sender, _) = watch::channel(Arc::new(config));
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn current(&self) -> Arc<DotEnvyConfig> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<DotEnvyConfig>> {
+        self.sender.subscribe()
+    }
+
+    /// Re-reads `.env`, `CONFIG_FILE` and the environment and publishes the reloadable
+    /// part of the result. An invalid config is rejected and the current one is kept.
+    ///
+    /// The process environment is never modified and still takes precedence, so an
+    /// edit to `.env` only applies to keys the environment does not set itself.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let loaded = config::load()?;
+
+        let current = self.current();
+        let next = merge_reloadable(&current, loaded);
+        self.sender.send_replace(Arc::new(next));
+        info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Calls `f` with the current config now and again after every reload.
+    pub fn on_change<F>(&self, f: F)
+    where
+        F: Fn(&DotEnvyConfig) + Send + 'static,
+    {
+        let mut receiver = self.subscribe();
+        f(&receiver.borrow_and_update());
+        tokio::spawn(async move {
+            while receiver.changed().await.is_ok() {
+                f(&receiver.borrow_and_update());
+            }
+        });
+    }
+
+    /// Keeps a value built from the config (e.g. a CORS layer) up to date across reloads.
+    pub fn derive<T, F>(&self, build: F) -> Derived<T>
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&DotEnvyConfig) -> T + Send + 'static,
+    {
+        let derived = Derived {
+            value: Arc::new(RwLock::new(Arc::new(build(&self.current())))),
+        };
+        let target = derived.clone();
+        self.on_change(move |config| {
+            let value = Arc::new(build(config));
+            *target.value.write().unwrap_or_else(|e| e.into_inner()) = value;
+        });
+        derived
+    }
+
+    /// Reloads the config on SIGHUP and whenever `.env` or `CONFIG_FILE` changes on disk.
+    pub fn watch(&self) {
+        #[cfg(unix)]
+        {
+            let handle = self.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{SignalKind, signal};
+
+                let mut hangup = match signal(SignalKind::hangup()) {
+                    Ok(hangup) => hangup,
+                    Err(e) => return error!("Failed to listen for SIGHUP: {}", e),
+                };
+                while hangup.recv().await.is_some() {
+                    info!("SIGHUP received, reloading config...");
+                    handle.reload_logged();
+                }
+            });
+        }
+
+        let handle = self.clone();
+        tokio::spawn(async move {
+            let mut files: Vec<(PathBuf, Option<SystemTime>)> = watched_files()
+                .into_iter()
+                .map(|path| {
+                    let modified = modified_at(&path);
+                    (path, modified)
+                })
+                .collect();
+
+            loop {
+                tokio::time::sleep(FILE_POLL_INTERVAL).await;
+
+                let mut changed = false;
+                for (path, last_modified) in files.iter_mut() {
+                    let modified = modified_at(path);
+                    if modified != *last_modified {
+                        info!("{} changed, reloading config...", path.display());
+                        *last_modified = modified;
+                        changed = true;
+                    }
+                }
+                if changed {
+                    handle.reload_logged();
+                }
+            }
+        });
+    }
+
+    fn reload_logged(&self) {
+        if let Err(e) = self.reload() {
+            error!("Config reload rejected, keeping the current config: {}", e);
+        }
+    }
+}
+
+/// A value rebuilt by `ConfigHandle::derive` whenever the config changes.
+pub struct Derived<T> {
+    value: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for Derived<T> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T> Derived<T> {
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+fn merge_reloadable(current: &DotEnvyConfig, loaded: DotEnvyConfig) -> DotEnvyConfig {
+    if loaded.server.port != current.server.port
+        || loaded.server.body_limit != current.server.body_limit
+        || loaded.server.path_prefix != current.server.path_prefix
+        || loaded.database != current.database
+        || loaded.message_queue.url != current.message_queue.url
+    {
+        warn!("Server, database and message queue settings changed, restart to apply them");
+    }
+
+    DotEnvyConfig {
+        server: Server {
+            timeout: loaded.server.timeout,
+            ..current.server.clone()
+        },
+        frontend: loaded.frontend,
+        logging: loaded.logging,
+        jwt: loaded.jwt,
+        errors: loaded.errors,
+        pagination: loaded.pagination,
+        stage: loaded.stage,
+        database: current.database.clone(),
+        message_queue: current.message_queue.clone(),
+    }
+}
+
+fn watched_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(path) = config::dotenv_path() {
+        files.push(path);
+    }
+    if let Ok(path) = std::env::var("CONFIG_FILE") {
+        files.push(PathBuf::from(path));
+    }
+    files
+}
+
+fn modified_at(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -1,11 +1,11 @@
 use anyhow::Result;
 use futures::future::BoxFuture;
 use futures_lite::StreamExt;
-use lapin::message::Delivery;
+use lapin::{message::Delivery, types::AMQPValue};
 use std::{sync::Arc, time::Duration};
-use tracing::{error, info};
+use tracing::{Instrument, error, info, info_span};
 
-use crate::app_state::AppState;
+use crate::{app_state::AppState, request_id::RequestId};
 
 pub type ConsumerFn = fn(Delivery, Arc<AppState>) -> BoxFuture<'static, Result<()>>;
 
@@ -24,7 +24,13 @@ pub fn init(queue_name: String, consumer_fn: ConsumerFn, state: Arc<AppState>) {
 
                 while let Some(delivery) = consumer.next().await {
                     let delivery = delivery?;
-                    match consumer_fn(delivery, state.clone()).await {
+                    let request_id = delivery_request_id(&delivery);
+                    let span = info_span!("message", queue = %queue_name, request_id = %request_id);
+                    let handled = request_id
+                        .scope(consumer_fn(delivery, state.clone()))
+                        .instrument(span)
+                        .await;
+                    match handled {
                         Ok(_) => {}
                         Err(err) => error!("Error in consumer: {}", err),
                     }
@@ -44,3 +50,25 @@ pub fn init(queue_name: String, consumer_fn: ConsumerFn, state: Arc<AppState>) {
         }
     });
 }
+
+/// Picks up the `x-request-id` header set by the outbox, or generates a new id.
+fn delivery_request_id(delivery: &Delivery) -> RequestId {
+    delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| {
+            headers
+                .inner()
+                .iter()
+                .find(|(key, _)| key.as_str() == "x-request-id")
+                .map(|(_, value)| value.clone())
+        })
+        .and_then(|value| match value {
+            AMQPValue::LongString(value) => {
+                RequestId::parse(&String::from_utf8_lossy(value.as_bytes()))
+            }
+            _ => None,
+        })
+        .unwrap_or_else(RequestId::generate)
+}
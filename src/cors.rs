@@ -1,8 +1,31 @@
-use axum::http::HeaderValue;
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
 use reqwest::{Method, header};
+use tower::{Layer, ServiceExt};
 use tower_http::cors::CorsLayer;
 
-use crate::config::{DotEnvyConfig, Stage};
+use crate::{
+    app_error::ERROR_ID_HEADER,
+    config::{DotEnvyConfig, Stage},
+    config_watch::Derived,
+    request_id::REQUEST_ID_HEADER,
+};
+
+/// Applies the CORS layer for the current config, so origin changes take effect on reload.
+pub async fn reloadable(
+    State(cors): State<Derived<CorsLayer>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match cors.get().layer(next).oneshot(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
 
 pub fn create_from_stage(stage: Stage, config: &DotEnvyConfig) -> CorsLayer {
     match stage {
@@ -21,7 +44,12 @@ pub fn create_cors_local_layer(config: &DotEnvyConfig) -> CorsLayer {
             Method::PATCH,
             Method::DELETE,
         ])
-        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([REQUEST_ID_HEADER, ERROR_ID_HEADER])
         .allow_credentials(true)
         .allow_origin(
             config
@@ -41,7 +69,12 @@ pub fn create_cors_production_layer(config: &DotEnvyConfig) -> CorsLayer {
             Method::PATCH,
             Method::DELETE,
         ])
-        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([REQUEST_ID_HEADER, ERROR_ID_HEADER])
         .allow_credentials(true)
         .allow_origin(
             config
@@ -61,7 +94,12 @@ pub fn create_cors_development_layer(config: &DotEnvyConfig) -> CorsLayer {
             Method::PATCH,
             Method::DELETE,
         ])
-        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([REQUEST_ID_HEADER, ERROR_ID_HEADER])
         .allow_credentials(true)
         .allow_origin(
             config
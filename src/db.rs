@@ -1,27 +1,340 @@
 use anyhow::Result;
-use diesel::{Connection, PgConnection};
-use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
-use std::time::Duration;
-use tracing::info;
+use diesel::{
+    Connection, ConnectionError, PgConnection, QueryResult,
+    dsl::sql,
+    sql_types::{Double, Nullable},
+};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+use futures::FutureExt;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
 
 /// Use diesel_async for pooled async connection, instead of
 use diesel_async::{
-    AsyncPgConnection,
-    pooled_connection::{AsyncDieselConnectionManager, bb8::Pool},
+    AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection,
+    pooled_connection::{
+        AsyncDieselConnectionManager, ManagerConfig,
+        bb8::{Pool, PooledConnection, RunError},
+    },
+    scoped_futures::ScopedBoxFuture,
 };
 
-// Use async PG connection instead of
-// pub type PgPoolSquad = Pool<ConnectionManager<PgConnection>>
-pub type DbPool = Pool<AsyncPgConnection>;
+use crate::{app_error::AppError, config::Database};
+
+/// Attempts made by `transaction` before a serialization failure or deadlock is
+/// returned to the caller.
+const TRANSACTION_ATTEMPTS: u32 = 4;
+const TRANSACTION_BASE_BACKOFF: Duration = Duration::from_millis(20);
+
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pool of async Postgres connections.
+///
+/// `get` checks out a connection like bb8's own `get`, and logs a warning when the
+/// wait exceeds `DATABASE_POOL_SLOW_ACQUIRE_MS`, which usually means the pool is
+/// too small for the load.
+#[derive(Clone)]
+pub struct DbPool {
+    // Use async PG connection instead of
+    // pub type PgPoolSquad = Pool<ConnectionManager<PgConnection>>
+    pool: Pool<AsyncPgConnection>,
+    slow_acquire_threshold: Duration,
+}
+
+impl DbPool {
+    pub async fn get(&self) -> Result<PooledConnection<'_, AsyncPgConnection>, RunError> {
+        let started = Instant::now();
+        let conn = self.pool.get().await;
+
+        let waited = started.elapsed();
+        if waited > self.slow_acquire_threshold {
+            let state = self.pool.state();
+            warn!(
+                "Waited {:?} for a database connection ({} open, {} idle)",
+                waited, state.connections, state.idle_connections
+            );
+        }
+        conn
+    }
+
+    /// The underlying bb8 pool, e.g. for its `state()`.
+    pub fn inner(&self) -> &Pool<AsyncPgConnection> {
+        &self.pool
+    }
+}
+
+/// Migrations for the tables and columns owned by medbook-core (`refresh_tokens`,
+/// `outbox.request_id`). `bootstrap` runs them on startup; they expect the service's
+/// own migrations, which create `outbox`, to have been applied first.
+pub const CORE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
-pub async fn connect(database_url: &str) -> Result<DbPool> {
-    let config = AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(database_url);
-    let pool = Pool::builder()
-        .connection_timeout(Duration::from_secs(1))
-        .build(config)
-        .await;
+pub async fn connect(database: &Database) -> Result<DbPool> {
+    let pool = build_pool(
+        database.url.expose(),
+        database,
+        database.max_connections,
+        Vec::new(),
+        true,
+    )
+    .await?;
     info!("Connected to database");
-    Ok(pool?)
+    Ok(pool)
+}
+
+/// Connects to `DATABASE_REPLICA_URL`, or returns `None` when no replica is configured.
+///
+/// Unlike `connect` this does not fail when the replica is unreachable at startup;
+/// reads go to the primary until it comes up. Those reads use a second, read-only
+/// pool on the primary of the replica's size that opens connections only on demand,
+/// so a write through `AppState::read_conn` fails whether or not the replica is up.
+pub async fn connect_replica(database: &Database) -> Result<Option<ReplicaPool>> {
+    let Some(url) = &database.replica_url else {
+        return Ok(None);
+    };
+    // Hot standbys reject writes anyway, this also catches them on a replica that is not one.
+    let read_only = || vec!["SET default_transaction_read_only = on".to_string()];
+    let pool = build_pool(
+        url.expose(),
+        database,
+        database.replica_max_connections,
+        read_only(),
+        false,
+    )
+    .await?;
+    let fallback_settings = Database {
+        min_idle: None,
+        ..database.clone()
+    };
+    let fallback = build_pool(
+        database.url.expose(),
+        &fallback_settings,
+        database.replica_max_connections,
+        read_only(),
+        false,
+    )
+    .await?;
+    info!("Configured database replica");
+
+    Ok(Some(ReplicaPool {
+        pool,
+        fallback,
+        max_lag: Duration::from_secs(database.replica_max_lag),
+        health: Arc::new(Mutex::new(None)),
+    }))
+}
+
+async fn build_pool(
+    url: &str,
+    database: &Database,
+    max_size: u32,
+    mut setup: Vec<String>,
+    check: bool,
+) -> Result<DbPool> {
+    if let Some(statement_timeout) = database.statement_timeout {
+        setup.push(format!("SET statement_timeout = {}", statement_timeout));
+    }
+    let mut manager_config = ManagerConfig::<AsyncPgConnection>::default();
+    if !setup.is_empty() {
+        let setup = setup.join("; ");
+        manager_config.custom_setup = Box::new(move |url| {
+            let setup = setup.clone();
+            async move {
+                let mut conn = AsyncPgConnection::establish(url).await?;
+                conn.batch_execute(&setup)
+                    .await
+                    .map_err(ConnectionError::CouldntSetupConfiguration)?;
+                Ok(conn)
+            }
+            .boxed()
+        });
+    }
+    let config = AsyncDieselConnectionManager::new_with_config(url, manager_config);
+
+    // 0 disables the limit, as in Postgres' own timeout settings.
+    let limit = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+    let builder = Pool::builder()
+        .max_size(max_size)
+        .min_idle(database.min_idle.map(|min_idle| min_idle.min(max_size)))
+        .idle_timeout(limit(database.idle_timeout))
+        .max_lifetime(limit(database.max_lifetime))
+        .connection_timeout(Duration::from_millis(database.connection_timeout))
+        .test_on_check_out(database.test_on_checkout);
+    let pool = match check {
+        true => builder.build(config).await?,
+        false => builder.build_unchecked(config),
+    };
+
+    Ok(DbPool {
+        pool,
+        slow_acquire_threshold: Duration::from_millis(database.slow_acquire_threshold),
+    })
+}
+
+/// Pool of read replica connections that knows when the replica should not be used.
+///
+/// Whether the replica is reachable and within `DATABASE_REPLICA_MAX_LAG` is checked
+/// at most every `REPLICA_CHECK_INTERVAL`, so a replica that is down costs one
+/// connection timeout per interval rather than one per request.
+#[derive(Clone)]
+pub struct ReplicaPool {
+    pool: DbPool,
+    fallback: DbPool,
+    max_lag: Duration,
+    health: Arc<Mutex<Option<ReplicaHealth>>>,
+}
+
+#[derive(Clone, Copy)]
+struct ReplicaHealth {
+    usable: bool,
+    checked_at: Instant,
+}
+
+impl ReplicaPool {
+    /// A replica connection, or `None` when reads should go to the primary.
+    pub async fn get(&self) -> Option<PooledConnection<'_, AsyncPgConnection>> {
+        let health = *self.health.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = health.filter(|h| h.checked_at.elapsed() < REPLICA_CHECK_INTERVAL);
+        if fresh.is_some_and(|h| !h.usable) {
+            return None;
+        }
+
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(
+                    "Database replica unavailable, reading from the primary: {}",
+                    e
+                );
+                self.record(false);
+                return None;
+            }
+        };
+        if fresh.is_none() {
+            let usable = match replication_lag(&mut conn).await {
+                Ok(lag) if lag <= self.max_lag => true,
+                Ok(lag) => {
+                    warn!("Database replica is {:?} behind the primary", lag);
+                    false
+                }
+                Err(e) => {
+                    warn!("Failed to check database replica lag: {}", e);
+                    false
+                }
+            };
+            self.record(usable);
+            if !usable {
+                return None;
+            }
+        }
+        Some(conn)
+    }
+
+    /// The replica's own pool, bypassing the health check.
+    pub fn pool(&self) -> &DbPool {
+        &self.pool
+    }
+
+    /// Read-only connections to the primary, for reads while the replica is unusable.
+    pub fn fallback(&self) -> &DbPool {
+        &self.fallback
+    }
+
+    fn record(&self, usable: bool) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        if usable && health.is_some_and(|h| !h.usable) {
+            info!("Database replica is usable again");
+        }
+        *health = Some(ReplicaHealth {
+            usable,
+            checked_at: Instant::now(),
+        });
+    }
+}
+
+/// How far the replica's replay is behind. A replica that has replayed everything it
+/// received counts as current even if the primary has been idle for a while.
+async fn replication_lag(conn: &mut AsyncPgConnection) -> QueryResult<Duration> {
+    let seconds = diesel::select(sql::<Nullable<Double>>(
+        "CASE WHEN NOT pg_is_in_recovery() \
+            OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+         ELSE EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) END::float8",
+    ))
+    .get_result::<Option<f64>>(conn)
+    .await?;
+    Ok(Duration::from_secs_f64(seconds.unwrap_or(0.0).max(0.0)))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Isolation {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Runs `f` in a transaction at the given isolation level on a pooled connection.
+///
+/// When the transaction fails with a serialization failure or deadlock it is rolled
+/// back and `f` is run again, after an exponentially growing pause. Any other error
+/// rolls back and is returned as is.
+///
+/// ```ignore
+/// let order = db::transaction(&state.db_pool, Isolation::Serializable, |conn| {
+///     async {
+///         let order = insert_order(conn, &new_order).await?;
+///         outbox::publish(conn, "orders.created".into(), &order).await?;
+///         Ok(order)
+///     }
+///     .scope_boxed()
+/// })
+/// .await?;
+/// ```
+pub async fn transaction<'b, T, F>(pool: &DbPool, isolation: Isolation, f: F) -> Result<T, AppError>
+where
+    F: for<'r> Fn(&'r mut AsyncPgConnection) -> ScopedBoxFuture<'b, 'r, Result<T, AppError>>
+        + Send
+        + Sync,
+    T: Send + 'b,
+{
+    let mut conn = pool
+        .get()
+        .await
+        .map_err(|_| AppError::ServiceUnreachable("database".into()))?;
+
+    let mut attempt = 1;
+    loop {
+        let mut builder = conn.build_transaction();
+        builder = match isolation {
+            Isolation::ReadCommitted => builder.read_committed(),
+            Isolation::RepeatableRead => builder.repeatable_read(),
+            Isolation::Serializable => builder.serializable(),
+        };
+
+        match builder.run(|conn| f(conn)).await {
+            Err(e) => match retry_backoff(&e, attempt) {
+                Some(backoff) => {
+                    warn!(
+                        "Transaction attempt {} failed ({}), retrying in {:?}",
+                        attempt, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before running a transaction again after attempt `attempt` failed
+/// with `error`, or `None` when it must not be retried.
+fn retry_backoff(error: &AppError, attempt: u32) -> Option<Duration> {
+    (error.is_retryable() && attempt < TRANSACTION_ATTEMPTS)
+        .then(|| TRANSACTION_BASE_BACKOFF * 2u32.pow(attempt - 1))
 }
 
 /// รัน migrations แบบ synchronous ใน thread แยก (เสถียร/ชัวร์)
@@ -48,3 +361,41 @@ pub async fn run_migrations_blocking(
 
     Ok(applied)
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    use super::*;
+
+    fn serialization_failure() -> DieselError {
+        DieselError::DatabaseError(
+            DatabaseErrorKind::SerializationFailure,
+            Box::new("could not serialize access".to_string()),
+        )
+    }
+
+    #[test]
+    fn serialization_failures_wrapped_in_anyhow_are_retried() {
+        let wrapped = Err::<(), _>(serialization_failure())
+            .context("Failed to create outbox")
+            .unwrap_err();
+        let error = AppError::from(wrapped);
+
+        assert!(error.is_retryable());
+        assert_eq!(retry_backoff(&error, 1), Some(TRANSACTION_BASE_BACKOFF));
+        assert_eq!(retry_backoff(&error, 2), Some(TRANSACTION_BASE_BACKOFF * 2));
+        assert_eq!(retry_backoff(&error, TRANSACTION_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn app_errors_wrapped_in_anyhow_keep_their_kind() {
+        let wrapped = anyhow::Error::new(AppError::Retryable("lock timeout".into()));
+        assert!(retry_backoff(&AppError::from(wrapped), 1).is_some());
+
+        let other = AppError::from(anyhow::anyhow!("payload did not serialize"));
+        assert!(matches!(other, AppError::Other(_)));
+        assert_eq!(retry_backoff(&other, 1), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{
+        Arc, RwLock,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+static ERROR_REPORTER: RwLock<Option<Arc<dyn ErrorReporter>>> = RwLock::new(None);
+
+/// A server error as seen by an `ErrorReporter`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    /// Id returned to the client, so a support request can be matched to this report.
+    pub error_id: String,
+    pub request_id: Option<String>,
+    pub status: u16,
+    pub code: &'static str,
+    /// The error followed by each of its causes.
+    pub chain: Vec<String>,
+    pub backtrace: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+/// Sink for server errors, e.g. an error tracker or a local file.
+///
+/// Reporting runs inside the response path, so implementations should hand the
+/// report off quickly rather than block on network calls.
+pub trait ErrorReporter: Send + Sync + 'static {
+    fn report(&self, report: &ErrorReport);
+}
+
+/// Registers the reporter every 5xx `AppError` is sent to, replacing any previous one.
+pub fn set_error_reporter(reporter: impl ErrorReporter) {
+    *ERROR_REPORTER.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(reporter));
+}
+
+pub(crate) fn report(report: &ErrorReport) {
+    let reporter = ERROR_REPORTER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(reporter) = reporter {
+        reporter.report(report);
+    }
+}
+
+/// Reports waiting for the file writer; further reports are dropped with a warning.
+const FILE_QUEUE_SIZE: usize = 1024;
+
+/// Appends every report as a JSON line to a file.
+///
+/// Reports are queued to a background thread that does the writing, so a slow disk
+/// never delays a response.
+pub struct FileErrorReporter {
+    reports: SyncSender<ErrorReport>,
+}
+
+impl FileErrorReporter {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (reports, queued) = mpsc::sync_channel(FILE_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("error-reporter".into())
+            .spawn(move || write_reports(file, queued))?;
+        Ok(Self { reports })
+    }
+}
+
+impl ErrorReporter for FileErrorReporter {
+    fn report(&self, report: &ErrorReport) {
+        match self.reports.try_send(report.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(report)) => {
+                warn!("Error report queue is full, dropping {}", report.error_id)
+            }
+            Err(TrySendError::Disconnected(report)) => {
+                warn!(
+                    "Error report writer has stopped, dropping {}",
+                    report.error_id
+                )
+            }
+        }
+    }
+}
+
+/// Runs until every `FileErrorReporter` sending to it is dropped.
+fn write_reports(mut file: File, reports: Receiver<ErrorReport>) {
+    for report in reports {
+        let written = serde_json::to_string(&report)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(file, "{}", line)?));
+        if let Err(e) = written {
+            warn!("Failed to write error report {}: {}", report.error_id, e);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use axum::http;
+use reqwest::{
+    Body, Client, IntoUrl, Method, Request, RequestBuilder, Response, Url,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    config::{Jwt, Secret},
+    jwt_authentication::{self, Roles, TokenSettings},
+    jwt_keys::KeySet,
+    request_id::{REQUEST_ID_HEADER, RequestId},
+};
+
+/// Thin wrapper around `reqwest::Client` used for service-to-service calls.
+///
+/// Every request built through it carries the current `X-Request-Id`, so a call
+/// can be followed across services in the logs. With `with_service_tokens` they also
+/// carry a `Service` token as `Authorization: Bearer` when they go to one of
+/// `INTERNAL_SERVICE_HOSTS` and the caller sets no `Authorization` header itself.
+#[derive(Debug, Clone, Default)]
+pub struct HttpClient {
+    inner: Client,
+    service_tokens: Option<Arc<ServiceTokens>>,
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_service_tokens(mut self, service_tokens: ServiceTokens) -> Self {
+        self.service_tokens = Some(Arc::new(service_tokens));
+        self
+    }
+
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> ServiceRequest {
+        let mut builder = self.inner.request(method, url);
+
+        if let Some(request_id) = RequestId::current() {
+            builder = builder.header(REQUEST_ID_HEADER, request_id.as_str());
+        }
+        ServiceRequest {
+            builder,
+            service_tokens: self.service_tokens.clone(),
+        }
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> ServiceRequest {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> ServiceRequest {
+        self.request(Method::POST, url)
+    }
+
+    pub fn put<U: IntoUrl>(&self, url: U) -> ServiceRequest {
+        self.request(Method::PUT, url)
+    }
+
+    pub fn patch<U: IntoUrl>(&self, url: U) -> ServiceRequest {
+        self.request(Method::PATCH, url)
+    }
+
+    pub fn delete<U: IntoUrl>(&self, url: U) -> ServiceRequest {
+        self.request(Method::DELETE, url)
+    }
+
+    /// The underlying client, for requests that must not carry the request id.
+    pub fn inner(&self) -> &Client {
+        &self.inner
+    }
+}
+
+/// A `reqwest::RequestBuilder` that attaches the service token when it is built or
+/// sent, after the caller has set its own headers. Builder methods not forwarded here
+/// are reachable through `map`.
+pub struct ServiceRequest {
+    builder: RequestBuilder,
+    service_tokens: Option<Arc<ServiceTokens>>,
+}
+
+impl ServiceRequest {
+    pub fn header<K, V>(self, key: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: Into<http::Error>,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+    {
+        self.map(|builder| builder.header(key, value))
+    }
+
+    pub fn headers(self, headers: HeaderMap) -> Self {
+        self.map(|builder| builder.headers(headers))
+    }
+
+    /// Sets the caller's own bearer token, which replaces the service token.
+    pub fn bearer_auth<T: fmt::Display>(self, token: T) -> Self {
+        self.map(|builder| builder.bearer_auth(token))
+    }
+
+    pub fn basic_auth<U: fmt::Display, P: fmt::Display>(
+        self,
+        username: U,
+        password: Option<P>,
+    ) -> Self {
+        self.map(|builder| builder.basic_auth(username, password))
+    }
+
+    pub fn query<T: Serialize + ?Sized>(self, query: &T) -> Self {
+        self.map(|builder| builder.query(query))
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, json: &T) -> Self {
+        self.map(|builder| builder.json(json))
+    }
+
+    pub fn form<T: Serialize + ?Sized>(self, form: &T) -> Self {
+        self.map(|builder| builder.form(form))
+    }
+
+    pub fn body<T: Into<Body>>(self, body: T) -> Self {
+        self.map(|builder| builder.body(body))
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.map(|builder| builder.timeout(timeout))
+    }
+
+    /// Applies any other `RequestBuilder` method.
+    pub fn map(mut self, f: impl FnOnce(RequestBuilder) -> RequestBuilder) -> Self {
+        self.builder = f(self.builder);
+        self
+    }
+
+    pub fn build(self) -> reqwest::Result<Request> {
+        let mut request = self.builder.build()?;
+        attach_service_token(self.service_tokens.as_deref(), &mut request);
+        Ok(request)
+    }
+
+    pub async fn send(self) -> reqwest::Result<Response> {
+        let (client, request) = self.builder.build_split();
+        let mut request = request?;
+        attach_service_token(self.service_tokens.as_deref(), &mut request);
+        client.execute(request).await
+    }
+}
+
+/// Adds the service token to a request for an internal host that has no
+/// `Authorization` header of its own.
+fn attach_service_token(service_tokens: Option<&ServiceTokens>, request: &mut Request) {
+    let Some(service_tokens) = service_tokens else {
+        return;
+    };
+    if request.headers().contains_key(header::AUTHORIZATION)
+        || !service_tokens.is_internal(request.url())
+    {
+        return;
+    }
+
+    match service_tokens.header() {
+        Ok(value) => {
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        Err(e) => warn!("Sending request without a service token: {:#}", e),
+    }
+}
+
+/// Mints the `Service` tokens attached by `HttpClient` and reuses each one for half
+/// of its lifetime, so a call never carries a token about to expire.
+#[derive(Debug)]
+pub struct ServiceTokens {
+    keys: KeySet,
+    settings: TokenSettings,
+    service_name: String,
+    scopes: Vec<String>,
+    ttl: Duration,
+    internal_hosts: Vec<String>,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    header: Secret,
+    renew_at: Instant,
+}
+
+impl ServiceTokens {
+    pub fn new(
+        keys: KeySet,
+        settings: TokenSettings,
+        service_name: &str,
+        scopes: Vec<String>,
+        ttl: Duration,
+        internal_hosts: Vec<String>,
+    ) -> Self {
+        Self {
+            keys,
+            settings,
+            service_name: service_name.to_string(),
+            scopes,
+            ttl,
+            internal_hosts,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Builds the token source for a service that calls others, i.e. sets
+    /// `SERVICE_AUDIENCE` and `INTERNAL_SERVICE_HOSTS`, or returns `None` when it sets
+    /// neither. Tokens are signed with the `JWT_SERVICE_*` keys and issued for
+    /// `SERVICE_AUDIENCE`, not for the `JWT_AUDIENCE` of user tokens.
+    ///
+    /// A service that only receives calls may still set `JWT_SERVICE_SECRET` to verify
+    /// them without signing anything itself.
+    pub fn from_config(service_name: &str, jwt: &Jwt) -> Result<Option<Self>> {
+        match (
+            jwt.service_audience.is_empty(),
+            jwt.internal_hosts.is_empty(),
+        ) {
+            (true, true) => return Ok(None),
+            (true, false) => bail!("SERVICE_AUDIENCE must list the services this service calls"),
+            (false, true) => bail!(
+                "INTERNAL_SERVICE_HOSTS must list the hosts of the services this service calls"
+            ),
+            (false, false) => {}
+        }
+
+        Ok(Some(Self::new(
+            KeySet::from_env(Roles::Service.key_prefix())?,
+            TokenSettings {
+                audience: jwt.service_audience.clone(),
+                ..jwt.into()
+            },
+            service_name,
+            jwt.service_scopes.clone(),
+            Duration::from_secs(jwt.service_token_ttl),
+            jwt.internal_hosts.clone(),
+        )))
+    }
+
+    /// Whether calls to `url` carry the token, i.e. its host, or host and port, is one
+    /// of the internal hosts.
+    pub fn is_internal(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host_and_port = url
+            .port_or_known_default()
+            .map(|port| format!("{}:{}", host, port));
+
+        self.internal_hosts.iter().any(|internal| {
+            internal.eq_ignore_ascii_case(host)
+                || host_and_port
+                    .as_deref()
+                    .is_some_and(|host_and_port| internal.eq_ignore_ascii_case(host_and_port))
+        })
+    }
+
+    fn header(&self) -> Result<HeaderValue> {
+        let mut value = HeaderValue::try_from(self.token()?)?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+
+    /// The `Authorization` header value for the next call.
+    pub fn token(&self) -> Result<String> {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(token) = cached.as_ref().filter(|t| t.renew_at > Instant::now()) {
+            return Ok(token.header.expose().to_string());
+        }
+
+        let token = jwt_authentication::issue_service_token(
+            &self.keys,
+            &self.settings,
+            &self.service_name,
+            &self.scopes,
+            self.ttl,
+        )?;
+        let header = format!("Bearer {}", token);
+        *cached = Some(CachedToken {
+            header: Secret::new(header.clone()),
+            renew_at: Instant::now() + self.ttl / 2,
+        });
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> HttpClient {
+        let settings = TokenSettings {
+            access: Duration::from_secs(60),
+            refresh: Duration::from_secs(60),
+            issuer: "medbook".into(),
+            audience: vec!["appointments".into()],
+            leeway: Duration::ZERO,
+        };
+        HttpClient::new().with_service_tokens(ServiceTokens::new(
+            KeySet::hmac(&Secret::new("secret")),
+            settings,
+            "billing",
+            Vec::new(),
+            Duration::from_secs(60),
+            vec!["appointments".into(), "records:8443".into()],
+        ))
+    }
+
+    fn authorization(request: ServiceRequest) -> Vec<String> {
+        request
+            .build()
+            .unwrap()
+            .headers()
+            .get_all(header::AUTHORIZATION)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn service_token_only_goes_to_internal_hosts() {
+        let client = client();
+
+        let internal = authorization(client.get("http://appointments/slots"));
+        assert!(internal[0].starts_with("Bearer "));
+        assert_eq!(
+            authorization(client.get("https://records:8443/files")).len(),
+            1
+        );
+        assert!(authorization(client.get("https://records/files")).is_empty());
+        assert!(authorization(client.get("https://payments.example.com")).is_empty());
+    }
+
+    #[test]
+    fn caller_authorization_is_kept() {
+        let client = client();
+        let request = client
+            .get("http://appointments/slots")
+            .bearer_auth("user-token");
+
+        assert_eq!(authorization(request), ["Bearer user-token"]);
+    }
+}
@@ -1,6 +1,22 @@
-use anyhow::Result;
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
-use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail, ensure};
+use axum::http::HeaderValue;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{self, DoctorsSecret, Jwt, PatientsSecret, Secret, Stage},
+    jwt_keys::KeySet,
+};
+
+pub const ACCESS_TOKEN_COOKIE: &str = "act";
+pub const REFRESH_TOKEN_COOKIE: &str = "rft";
+
+/// `aud` of refresh tokens, so they are never accepted as access tokens.
+const REFRESH_AUDIENCE: &str = "refresh";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Passport {
@@ -12,14 +28,207 @@ pub struct Passport {
 pub struct Claims {
     pub sub: String,
     pub role: Roles,
+    /// `iss`, `aud` and `nbf` are missing on tokens issued before they were added.
+    #[serde(default)]
+    pub iss: String,
+    /// A single audience is sent as a plain string by other issuers.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub aud: Vec<String>,
     pub exp: usize,
+    #[serde(default)]
+    pub nbf: usize,
     pub iat: usize,
+    /// Unique id of this token. `None` on tokens issued before rotation existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Token family: every pair obtained by rotating the same login shares it,
+    /// so the whole chain can be revoked at once. `None` like `jti`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fam: Option<String>,
+    /// What the bearer may do, e.g. `appointments:read`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(aud) => vec![aud],
+        OneOrMany::Many(aud) => aud,
+    })
+}
+
+impl Claims {
+    /// Whether the token grants `scope`, either exactly or through a `resource:*`
+    /// wildcard.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| {
+            granted == scope
+                || granted
+                    .strip_suffix('*')
+                    .is_some_and(|prefix| prefix.ends_with(':') && scope.starts_with(prefix))
+        })
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Roles {
     Patient,
     Doctor,
+    /// Clinic staff, e.g. receptionists.
+    Staff,
+    Admin,
+    /// Another service calling on its own behalf; `sub` is the service name.
+    Service,
+}
+
+impl Roles {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Roles::Patient => "Patient",
+            Roles::Doctor => "Doctor",
+            Roles::Staff => "Staff",
+            Roles::Admin => "Admin",
+            Roles::Service => "Service",
+        }
+    }
+
+    /// Prefix of the environment variables holding this role's keys.
+    pub fn key_prefix(&self) -> &'static str {
+        match self {
+            Roles::Patient => "JWT_PATIENT",
+            Roles::Doctor => "JWT_DOCTOR",
+            Roles::Staff => "JWT_STAFF",
+            Roles::Admin => "JWT_ADMIN",
+            Roles::Service => "JWT_SERVICE",
+        }
+    }
+}
+
+/// Keys for the access and refresh tokens of one role.
+///
+/// Access tokens use the role's `KeySet`, which may be asymmetric. Refresh tokens are
+/// only ever checked by the issuing service, so they always use an HMAC secret.
+#[derive(Debug, Clone)]
+pub struct TokenKeys {
+    pub role: Roles,
+    pub access: KeySet,
+    pub refresh_secret: Secret,
+}
+
+impl From<PatientsSecret> for TokenKeys {
+    fn from(secrets: PatientsSecret) -> Self {
+        Self {
+            role: Roles::Patient,
+            access: KeySet::hmac(&secrets.secret),
+            refresh_secret: secrets.refresh_secret,
+        }
+    }
+}
+
+impl From<DoctorsSecret> for TokenKeys {
+    fn from(secrets: DoctorsSecret) -> Self {
+        Self {
+            role: Roles::Doctor,
+            access: KeySet::hmac(&secrets.secret),
+            refresh_secret: secrets.refresh_secret,
+        }
+    }
+}
+
+impl TokenKeys {
+    /// Loads the keys of `role` from the environment.
+    pub fn for_role(role: &Roles) -> Result<Self> {
+        Ok(Self {
+            role: role.clone(),
+            access: KeySet::from_env(role.key_prefix())?,
+            refresh_secret: config::get_refresh_secret_env(role.key_prefix())?,
+        })
+    }
+}
+
+/// Lifetimes, issuer and audience of issued tokens.
+#[derive(Debug, Clone)]
+pub struct TokenSettings {
+    pub access: Duration,
+    pub refresh: Duration,
+    pub issuer: String,
+    pub audience: Vec<String>,
+    pub leeway: Duration,
+}
+
+impl From<&Jwt> for TokenSettings {
+    fn from(jwt: &Jwt) -> Self {
+        Self {
+            access: Duration::from_secs(jwt.access_ttl),
+            refresh: Duration::from_secs(jwt.refresh_ttl),
+            issuer: jwt.issuer.clone(),
+            audience: jwt.audience.clone(),
+            leeway: Duration::from_secs(jwt.leeway),
+        }
+    }
+}
+
+/// What a service checks on a token besides its signature.
+///
+/// Only `exp` and `sub` are required. `nbf`, `iss` and `aud` are checked when present,
+/// so tokens issued before they were added stay valid until they expire. A token for
+/// none of `audience` is rejected.
+#[derive(Debug, Clone)]
+pub struct TokenValidation {
+    pub issuer: String,
+    pub audience: Vec<String>,
+    /// Clock skew tolerated when checking `exp` and `nbf`.
+    pub leeway: Duration,
+}
+
+impl TokenValidation {
+    pub fn for_service(jwt: &Jwt, service_name: &str) -> Self {
+        Self {
+            issuer: jwt.issuer.clone(),
+            audience: vec![service_name.to_string()],
+            leeway: Duration::from_secs(jwt.leeway),
+        }
+    }
+
+    /// Accepts tokens for any service of `JWT_AUDIENCE`, for services that do not
+    /// know their own name, e.g. routers built without `bootstrap`.
+    pub fn from_env() -> Result<Self> {
+        let jwt = config::get_jwt_env()?;
+        ensure!(
+            !jwt.audience.is_empty(),
+            "JWT_AUDIENCE must list the services tokens are accepted for"
+        );
+        Ok(Self {
+            issuer: jwt.issuer,
+            audience: jwt.audience,
+            leeway: Duration::from_secs(jwt.leeway),
+        })
+    }
+
+    pub fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&self.audience);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        validation
+    }
+
+    fn for_refresh(settings: &TokenSettings) -> Self {
+        Self {
+            issuer: settings.issuer.clone(),
+            audience: vec![REFRESH_AUDIENCE.to_string()],
+            leeway: settings.leeway,
+        }
+    }
 }
 
 pub fn generate_token(secret: String, claims: &Claims) -> Result<String> {
@@ -31,11 +240,324 @@ pub fn generate_token(secret: String, claims: &Claims) -> Result<String> {
     Ok(token)
 }
 
-pub fn verify_token(secret: String, token: String) -> Result<Claims> {
+pub fn verify_token(secret: String, token: String, validation: &TokenValidation) -> Result<Claims> {
     let result = decode(
         &token,
         &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
+        &validation.validation(Algorithm::HS256),
     )?;
     Ok(result.claims)
 }
+
+/// Verifies an access token of `role`. A token carrying another role is rejected even
+/// if its signature is valid, e.g. when two roles were configured with the same key.
+pub fn verify_access_token(
+    keys: &KeySet,
+    validation: &TokenValidation,
+    role: &Roles,
+    token: &str,
+) -> Result<Claims> {
+    let claims: Claims = keys.verify(token, &validation.validation(Algorithm::HS256))?;
+    ensure_role(&claims, role)?;
+    Ok(claims)
+}
+
+fn ensure_role(claims: &Claims, role: &Roles) -> Result<()> {
+    ensure!(
+        claims.role == *role,
+        "Token has the {:?} role, expected {:?}",
+        claims.role,
+        role
+    );
+    Ok(())
+}
+
+/// Mints an access token and a refresh token for `sub` with the role of `keys` and the
+/// given scopes, starting a new token family.
+///
+/// Use `token_store::issue_passport` instead when the refresh token must be revocable.
+pub fn issue_passport(
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    sub: String,
+    scopes: Vec<String>,
+) -> Result<Passport> {
+    let family = Uuid::new_v4().to_string();
+    issue_passport_in_family(keys, settings, sub, scopes, &family).map(|(passport, _)| passport)
+}
+
+/// Mints a token pair belonging to `family` and returns it with the refresh token's claims.
+///
+/// Only the access token carries the scopes; rotation asks for the subject's current
+/// ones, so a change of permissions takes effect at the next refresh.
+pub fn issue_passport_in_family(
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    sub: String,
+    scopes: Vec<String>,
+    family: &str,
+) -> Result<(Passport, Claims)> {
+    if settings.audience.is_empty() {
+        bail!("JWT_AUDIENCE must list the services tokens are issued for");
+    }
+
+    let now = Utc::now().timestamp() as usize;
+    let claims = |lifetime: Duration, aud: Vec<String>| Claims {
+        sub: sub.clone(),
+        role: keys.role.clone(),
+        iss: settings.issuer.clone(),
+        aud,
+        iat: now,
+        nbf: now,
+        exp: now + lifetime.as_secs() as usize,
+        jti: Some(Uuid::new_v4().to_string()),
+        fam: Some(family.to_string()),
+        scopes: scopes.clone(),
+    };
+
+    let refresh_claims = Claims {
+        scopes: Vec::new(),
+        ..claims(settings.refresh, vec![REFRESH_AUDIENCE.to_string()])
+    };
+    let passport = Passport {
+        access_token: keys
+            .access
+            .sign(&claims(settings.access, settings.audience.clone()))?,
+        refresh_token: generate_token(keys.refresh_secret.expose().to_string(), &refresh_claims)?,
+    };
+    Ok((passport, refresh_claims))
+}
+
+/// Mints a short-lived `Service` token for calls from `service_name` to the services in
+/// `settings.audience`. Service tokens have no refresh token; a new one is minted
+/// when the old one expires.
+pub fn issue_service_token(
+    keys: &KeySet,
+    settings: &TokenSettings,
+    service_name: &str,
+    scopes: &[String],
+    ttl: Duration,
+) -> Result<String> {
+    if settings.audience.is_empty() {
+        bail!("SERVICE_AUDIENCE must list the services this service calls");
+    }
+
+    let now = Utc::now().timestamp() as usize;
+    let jti = Uuid::new_v4().to_string();
+    keys.sign(&Claims {
+        sub: service_name.to_string(),
+        role: Roles::Service,
+        iss: settings.issuer.clone(),
+        aud: settings.audience.clone(),
+        iat: now,
+        nbf: now,
+        exp: now + ttl.as_secs() as usize,
+        // Every service token is its own family, so revocation checks never match it.
+        fam: Some(jti.clone()),
+        jti: Some(jti),
+        scopes: scopes.to_vec(),
+    })
+}
+
+/// Checks a refresh token against the refresh secret and role of `keys` and returns
+/// its claims.
+pub fn verify_refresh_token(
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    refresh_token: &str,
+) -> Result<Claims> {
+    let claims = verify_token(
+        keys.refresh_secret.expose().to_string(),
+        refresh_token.to_string(),
+        &TokenValidation::for_refresh(settings),
+    )
+    .context("Invalid refresh token")?;
+    ensure_role(&claims, &keys.role)?;
+    Ok(claims)
+}
+
+/// Exchanges a valid refresh token for a new access/refresh token pair in the same family.
+/// `current_scopes` returns the scopes the subject holds now, so revoked ones are not
+/// carried over from the old token.
+///
+/// This does not prevent the old refresh token from being used again; use
+/// `token_store::rotate_passport` for reuse detection.
+pub fn rotate_passport(
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    refresh_token: &str,
+    current_scopes: impl FnOnce(&str) -> Result<Vec<String>>,
+) -> Result<Passport> {
+    let claims = verify_refresh_token(keys, settings, refresh_token)?;
+    let scopes = current_scopes(&claims.sub)?;
+    // A token from before rotation existed has no family yet and starts one.
+    let family = claims.fam.unwrap_or_else(|| Uuid::new_v4().to_string());
+    issue_passport_in_family(keys, settings, claims.sub, scopes, &family)
+        .map(|(passport, _)| passport)
+}
+
+/// `Set-Cookie` headers carrying the passport.
+///
+/// Cookies are always HttpOnly. Outside `Local` they are also Secure; `Development`
+/// uses `SameSite=None` so a frontend on another site can call the API, while
+/// `Production` uses `SameSite=Strict`.
+pub fn passport_cookies(
+    passport: &Passport,
+    settings: &TokenSettings,
+    stage: &Stage,
+) -> Result<[HeaderValue; 2]> {
+    Ok([
+        build_cookie(
+            ACCESS_TOKEN_COOKIE,
+            &passport.access_token,
+            settings.access,
+            stage,
+        )?,
+        build_cookie(
+            REFRESH_TOKEN_COOKIE,
+            &passport.refresh_token,
+            settings.refresh,
+            stage,
+        )?,
+    ])
+}
+
+/// `Set-Cookie` headers that remove the passport cookies, e.g. on logout.
+pub fn clear_passport_cookies(stage: &Stage) -> Result<[HeaderValue; 2]> {
+    Ok([
+        build_cookie(ACCESS_TOKEN_COOKIE, "", Duration::ZERO, stage)?,
+        build_cookie(REFRESH_TOKEN_COOKIE, "", Duration::ZERO, stage)?,
+    ])
+}
+
+fn build_cookie(name: &str, value: &str, max_age: Duration, stage: &Stage) -> Result<HeaderValue> {
+    let attributes = match stage {
+        Stage::Local => "SameSite=Lax",
+        Stage::Development => "SameSite=None; Secure",
+        Stage::Production => "SameSite=Strict; Secure",
+    };
+
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; {}",
+        name,
+        value,
+        max_age.as_secs(),
+        attributes
+    );
+    HeaderValue::from_str(&cookie).context("Invalid cookie value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims_with_scopes(scopes: &[&str]) -> Claims {
+        Claims {
+            sub: "42".into(),
+            role: Roles::Doctor,
+            iss: "medbook".into(),
+            aud: vec!["appointments".into()],
+            exp: 0,
+            nbf: 0,
+            iat: 0,
+            jti: None,
+            fam: None,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn has_scope_matches_exact_scopes_and_resource_wildcards() {
+        let claims = claims_with_scopes(&["appointments:read", "prescriptions:*"]);
+
+        assert!(claims.has_scope("appointments:read"));
+        assert!(!claims.has_scope("appointments:write"));
+        assert!(claims.has_scope("prescriptions:write"));
+        assert!(!claims.has_scope("prescriptions"));
+        assert!(!claims.has_scope("prescriptionsx:write"));
+    }
+
+    #[test]
+    fn bare_wildcard_grants_nothing() {
+        assert!(!claims_with_scopes(&["*"]).has_scope("appointments:read"));
+    }
+
+    #[test]
+    fn rotation_uses_the_current_scopes() {
+        let keys = TokenKeys {
+            role: Roles::Doctor,
+            access: KeySet::hmac(&Secret::new("access")),
+            refresh_secret: Secret::new("refresh"),
+        };
+        let settings = TokenSettings {
+            access: Duration::from_secs(60),
+            refresh: Duration::from_secs(60),
+            issuer: "medbook".into(),
+            audience: vec!["appointments".into()],
+            leeway: Duration::ZERO,
+        };
+        let passport = issue_passport(
+            &keys,
+            &settings,
+            "42".into(),
+            vec!["prescriptions:write".into()],
+        )
+        .unwrap();
+
+        let rotated = rotate_passport(&keys, &settings, &passport.refresh_token, |sub| {
+            assert_eq!(sub, "42");
+            Ok(vec!["appointments:read".into()])
+        })
+        .unwrap();
+
+        let validation = TokenValidation {
+            issuer: settings.issuer.clone(),
+            audience: settings.audience.clone(),
+            leeway: Duration::ZERO,
+        };
+        let claims = verify_access_token(
+            &keys.access,
+            &validation,
+            &Roles::Doctor,
+            &rotated.access_token,
+        )
+        .unwrap();
+        assert_eq!(claims.scopes, ["appointments:read"]);
+    }
+
+    #[test]
+    fn audience_may_be_a_single_string() {
+        let claims: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "42", "role": "Patient", "iss": "medbook", "aud": "appointments",
+            "exp": 0, "nbf": 0, "iat": 0,
+        }))
+        .unwrap();
+        assert_eq!(claims.aud, ["appointments"]);
+    }
+
+    #[test]
+    fn tokens_without_iss_aud_and_nbf_are_still_accepted() {
+        let validation = TokenValidation {
+            issuer: "medbook".into(),
+            audience: vec!["appointments".into()],
+            leeway: Duration::ZERO,
+        };
+        let exp = Utc::now().timestamp() + 60;
+        let legacy = serde_json::json!({ "sub": "42", "role": "Patient", "exp": exp, "iat": 0 });
+        let token = encode(
+            &Header::default(),
+            &legacy,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        let claims = verify_token("secret".into(), token, &validation).unwrap();
+        assert!(claims.aud.is_empty());
+
+        let mut other_service = claims_with_scopes(&[]);
+        other_service.aud = vec!["billing".into()];
+        other_service.exp = exp as usize;
+        let token = generate_token("secret".into(), &other_service).unwrap();
+        assert!(verify_token("secret".into(), token, &validation).is_err());
+    }
+}
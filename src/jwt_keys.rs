@@ -0,0 +1,427 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::{Json, Router, routing};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+        PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+};
+use serde::{Serialize, de::DeserializeOwned};
+use simple_asn1::ASN1Block;
+
+use crate::config::{self, JwtKeys, Secret};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+
+impl KeyFamily {
+    fn of(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => KeyFamily::Hmac,
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => KeyFamily::Rsa,
+            Algorithm::ES256 | Algorithm::ES384 => KeyFamily::Ec,
+            Algorithm::EdDSA => KeyFamily::Ed,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SigningKey {
+    key_id: Option<String>,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+#[derive(Clone)]
+struct VerificationKey {
+    key_id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    /// Public form of the key; `None` for HMAC secrets, which are never published.
+    jwk: Option<Jwk>,
+}
+
+/// Keys used to sign and verify the access tokens of one role.
+///
+/// Holds at most one signing key and any number of verification keys, so a key can be
+/// rotated by publishing the new one while tokens signed with the old one are still
+/// accepted. Tokens carry the `kid` of their signing key when one is configured.
+#[derive(Clone)]
+pub struct KeySet {
+    signing: Option<SigningKey>,
+    verification: Vec<VerificationKey>,
+}
+
+impl fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key_ids: Vec<_> = self
+            .verification
+            .iter()
+            .map(|key| (key.key_id.as_deref(), key.algorithm))
+            .collect();
+        f.debug_struct("KeySet")
+            .field("can_sign", &self.signing.is_some())
+            .field("verification_keys", &key_ids)
+            .finish()
+    }
+}
+
+impl KeySet {
+    /// A shared-secret (HS256) key set, as used before asymmetric keys were supported.
+    pub fn hmac(secret: &Secret) -> Self {
+        let algorithm = Algorithm::HS256;
+        Self {
+            signing: Some(SigningKey {
+                key_id: None,
+                algorithm,
+                key: EncodingKey::from_secret(secret.expose().as_bytes()),
+            }),
+            verification: vec![VerificationKey {
+                key_id: None,
+                algorithm,
+                key: DecodingKey::from_secret(secret.expose().as_bytes()),
+                jwk: None,
+            }],
+        }
+    }
+
+    /// Loads the keys of a role prefix such as `JWT_PATIENT` from the environment.
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Self::from_config(&config::get_jwt_keys_env(prefix)?)
+    }
+
+    pub fn from_config(keys: &JwtKeys) -> Result<Self> {
+        let algorithm = keys.algorithm;
+        let key_id = keys.key_id.clone();
+
+        if KeyFamily::of(algorithm) == KeyFamily::Hmac {
+            let secret = keys.secret.as_ref().context("HMAC keys need a secret")?;
+            let mut set = Self::hmac(secret);
+            set.signing.iter_mut().for_each(|k| {
+                k.algorithm = algorithm;
+                k.key_id = key_id.clone();
+            });
+            set.verification.iter_mut().for_each(|k| {
+                k.algorithm = algorithm;
+                k.key_id = key_id.clone();
+            });
+            return Ok(set);
+        }
+
+        let signing = keys
+            .private_key
+            .as_ref()
+            .map(|pem| -> Result<SigningKey> {
+                Ok(SigningKey {
+                    key_id: key_id.clone(),
+                    algorithm,
+                    key: encoding_key(algorithm, pem.expose().as_bytes())?,
+                })
+            })
+            .transpose()?;
+
+        let public_key = keys
+            .public_key
+            .as_ref()
+            .context("Asymmetric keys need a public key")?;
+        let mut verification = vec![verification_key(algorithm, key_id, public_key)?];
+        for retired in &keys.retired_keys {
+            verification.push(verification_key(
+                algorithm,
+                Some(retired.key_id.clone()),
+                &retired.public_key,
+            )?);
+        }
+
+        Ok(Self {
+            signing,
+            verification,
+        })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let signing = self
+            .signing
+            .as_ref()
+            .context("No private key configured, this service cannot issue tokens")?;
+
+        let mut header = Header::new(signing.algorithm);
+        header.kid = signing.key_id.clone();
+        Ok(encode(&header, claims, &signing.key)?)
+    }
+
+    /// Verifies `token` with the key named by its `kid` header, or with every key of
+    /// the token's algorithm when it has none. Claims are checked against `validation`;
+    /// its algorithm list is replaced by the matching key's algorithm.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str, validation: &Validation) -> Result<T> {
+        let header = decode_header(token)?;
+
+        let mut last_error = None;
+        for key in self.verification.iter().filter(|key| {
+            key.algorithm == header.alg
+                && match (&header.kid, &key.key_id) {
+                    (Some(kid), Some(key_id)) => kid == key_id,
+                    _ => true,
+                }
+        }) {
+            let mut validation = validation.clone();
+            validation.algorithms = vec![key.algorithm];
+            match decode::<T>(token, &key.key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(match last_error {
+            Some(e) => e.into(),
+            None => anyhow!("No key matches the token's algorithm and key id"),
+        })
+    }
+
+    /// Public keys in JWKS form. HMAC keys are never included.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification
+                .iter()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Serves `GET /.well-known/jwks.json` with the public keys of the given key sets,
+/// so other services can verify tokens without holding any secret.
+pub fn jwks_router<S>(key_sets: impl IntoIterator<Item = Arc<KeySet>>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let jwks = JwkSet {
+        keys: key_sets
+            .into_iter()
+            .flat_map(|set| set.jwks().keys)
+            .collect(),
+    };
+
+    Router::new().route(
+        "/.well-known/jwks.json",
+        routing::get(move || async move { Json(jwks) }),
+    )
+}
+
+fn encoding_key(algorithm: Algorithm, pem: &[u8]) -> Result<EncodingKey> {
+    Ok(match KeyFamily::of(algorithm) {
+        KeyFamily::Rsa => EncodingKey::from_rsa_pem(pem)?,
+        KeyFamily::Ec => EncodingKey::from_ec_pem(pem)?,
+        KeyFamily::Ed => EncodingKey::from_ed_pem(pem)?,
+        KeyFamily::Hmac => bail!("HMAC keys are not loaded from PEM"),
+    })
+}
+
+fn verification_key(
+    algorithm: Algorithm,
+    key_id: Option<String>,
+    public_pem: &str,
+) -> Result<VerificationKey> {
+    let key = match KeyFamily::of(algorithm) {
+        KeyFamily::Rsa => DecodingKey::from_rsa_pem(public_pem.as_bytes())?,
+        KeyFamily::Ec => DecodingKey::from_ec_pem(public_pem.as_bytes())?,
+        KeyFamily::Ed => DecodingKey::from_ed_pem(public_pem.as_bytes())?,
+        KeyFamily::Hmac => bail!("HMAC keys are not loaded from PEM"),
+    };
+
+    Ok(VerificationKey {
+        jwk: Some(public_jwk(algorithm, key_id.clone(), public_pem)?),
+        key_id,
+        algorithm,
+        key,
+    })
+}
+
+/// Builds the JWK of a public key in SubjectPublicKeyInfo (`PUBLIC KEY`) or PKCS#1
+/// (`RSA PUBLIC KEY`) PEM form.
+fn public_jwk(algorithm: Algorithm, key_id: Option<String>, public_pem: &str) -> Result<Jwk> {
+    let pem = pem::parse(public_pem).context("Invalid public key PEM")?;
+    let key_bytes = match pem.tag() {
+        "RSA PUBLIC KEY" => pem.contents().to_vec(),
+        "PUBLIC KEY" => subject_public_key(pem.contents())?,
+        tag => bail!("Unsupported public key PEM type: {}", tag),
+    };
+
+    let parameters = match KeyFamily::of(algorithm) {
+        KeyFamily::Rsa => {
+            let (n, e) = rsa_components(&key_bytes)?;
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(n),
+                e: URL_SAFE_NO_PAD.encode(e),
+            })
+        }
+        KeyFamily::Ec => {
+            // Uncompressed point: 0x04 || x || y
+            let point = key_bytes
+                .strip_prefix(&[0x04])
+                .context("Only uncompressed EC public keys are supported")?;
+            let (x, y) = point.split_at(point.len() / 2);
+            AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: match algorithm {
+                    Algorithm::ES384 => EllipticCurve::P384,
+                    _ => EllipticCurve::P256,
+                },
+                x: URL_SAFE_NO_PAD.encode(x),
+                y: URL_SAFE_NO_PAD.encode(y),
+            })
+        }
+        KeyFamily::Ed => AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(&key_bytes),
+        }),
+        KeyFamily::Hmac => bail!("HMAC keys are never published"),
+    };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(format!("{:?}", algorithm).parse::<KeyAlgorithm>()?),
+            key_id,
+            ..Default::default()
+        },
+        algorithm: parameters,
+    })
+}
+
+/// Extracts the raw key from a SubjectPublicKeyInfo structure.
+fn subject_public_key(der: &[u8]) -> Result<Vec<u8>> {
+    let blocks = simple_asn1::from_der(der).context("Invalid public key DER")?;
+    match blocks.first() {
+        Some(ASN1Block::Sequence(_, fields)) => match fields.get(1) {
+            Some(ASN1Block::BitString(_, _, bytes)) => Ok(bytes.clone()),
+            _ => bail!("Public key is missing its key bits"),
+        },
+        _ => bail!("Public key is not a SubjectPublicKeyInfo"),
+    }
+}
+
+/// Extracts the modulus and exponent from a PKCS#1 RSAPublicKey.
+fn rsa_components(der: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let blocks = simple_asn1::from_der(der).context("Invalid RSA public key DER")?;
+    match blocks.first() {
+        Some(ASN1Block::Sequence(_, fields)) => match (fields.first(), fields.get(1)) {
+            (Some(ASN1Block::Integer(_, n)), Some(ASN1Block::Integer(_, e))) => {
+                Ok((n.to_bytes_be().1, e.to_bytes_be().1))
+            }
+            _ => bail!("RSA public key is missing its modulus or exponent"),
+        },
+        _ => bail!("RSA public key is not a sequence"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_SPKI: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCwTjE8TqpLcaPUSd0nDlRrqyKC
+YsTULGhlfOLjDGjA+4LzR1fmc7MuWX1bAz0NFglmmTb/16Zccl9nOmUfOqVBu3jb
+Utdt4FHKQ6kMhJEzJiIVvTzUbZLE1eCRu/Fia39kZUwtOD0dswfKJM4iB7czbSmi
+pIb3HO13cJMuhIcNAwIDAQAB
+-----END PUBLIC KEY-----";
+
+    // The same key as `RSA_SPKI`.
+    const RSA_PKCS1: &str = "-----BEGIN RSA PUBLIC KEY-----
+MIGJAoGBALBOMTxOqktxo9RJ3ScOVGurIoJixNQsaGV84uMMaMD7gvNHV+Zzsy5Z
+fVsDPQ0WCWaZNv/XplxyX2c6ZR86pUG7eNtS123gUcpDqQyEkTMmIhW9PNRtksTV
+4JG78WJrf2RlTC04PR2zB8okziIHtzNtKaKkhvcc7Xdwky6Ehw0DAgMBAAE=
+-----END RSA PUBLIC KEY-----";
+
+    const RSA_N: &str = "sE4xPE6qS3Gj1EndJw5Ua6sigmLE1CxoZXzi4wxowPuC80dX5nOzLll9WwM9DRYJZpk2_9emXHJfZzplHzqlQbt421LXbeBRykOpDISRMyYiFb081G2SxNXgkbvxYmt_ZGVMLTg9HbMHyiTOIge3M20poqSG9xztd3CTLoSHDQM";
+
+    const EC_SPKI: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE/LK/GRUQ6+avS6ibb4bAt0EvD0lX
+s4pRWF85m4vP3dpohIAF8gvXO9cfyrVSbCTb63+zJg4SsxP9Mon8CoxcVQ==
+-----END PUBLIC KEY-----";
+
+    fn public_keys(algorithm: Algorithm, public_key: &str) -> Result<KeySet> {
+        KeySet::from_config(&JwtKeys {
+            algorithm,
+            key_id: Some("2026-10".into()),
+            secret: None,
+            private_key: None,
+            public_key: Some(public_key.into()),
+            retired_keys: Vec::new(),
+        })
+    }
+
+    fn rsa_parameters(keys: &KeySet) -> (String, String) {
+        match &keys.jwks().keys[0].algorithm {
+            AlgorithmParameters::RSA(rsa) => (rsa.n.clone(), rsa.e.clone()),
+            other => panic!("expected an RSA key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rsa_jwk_from_subject_public_key_info() {
+        let keys = public_keys(Algorithm::RS256, RSA_SPKI).unwrap();
+
+        let jwk = &keys.jwks().keys[0];
+        assert_eq!(jwk.common.key_id.as_deref(), Some("2026-10"));
+        assert_eq!(jwk.common.key_algorithm, Some(KeyAlgorithm::RS256));
+        assert_eq!(rsa_parameters(&keys), (RSA_N.into(), "AQAB".into()));
+    }
+
+    #[test]
+    fn rsa_jwk_from_pkcs1() {
+        let keys = public_keys(Algorithm::RS256, RSA_PKCS1).unwrap();
+
+        assert_eq!(rsa_parameters(&keys), (RSA_N.into(), "AQAB".into()));
+    }
+
+    #[test]
+    fn ec_jwk_from_subject_public_key_info() {
+        let keys = public_keys(Algorithm::ES256, EC_SPKI).unwrap();
+
+        match &keys.jwks().keys[0].algorithm {
+            AlgorithmParameters::EllipticCurve(ec) => {
+                assert_eq!(ec.curve, EllipticCurve::P256);
+                assert_eq!(ec.x, "_LK_GRUQ6-avS6ibb4bAt0EvD0lXs4pRWF85m4vP3do");
+                assert_eq!(ec.y, "aISABfIL1zvXH8q1Umwk2-t_syYOErMT_TKJ_AqMXFU");
+            }
+            other => panic!("expected an EC key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_public_keys_are_rejected() {
+        assert!(public_jwk(Algorithm::RS256, None, "not a pem").is_err());
+        // A SubjectPublicKeyInfo cut off after its first bytes.
+        assert!(subject_public_key(&[0x30, 0x81, 0x9f, 0x30, 0x0d]).is_err());
+        // An RSAPublicKey sequence holding only the modulus.
+        assert!(rsa_components(&[0x30, 0x03, 0x02, 0x01, 0x05]).is_err());
+        // An EC key passed off as RSA.
+        assert!(public_keys(Algorithm::RS256, EC_SPKI).is_err());
+    }
+
+    #[test]
+    fn hmac_keys_are_not_published() {
+        let keys = KeySet::hmac(&Secret::new("secret"));
+
+        assert!(keys.jwks().keys.is_empty());
+    }
+}
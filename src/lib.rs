@@ -1,13 +1,22 @@
 pub mod aliases;
 pub mod app_error;
 pub mod app_state;
+pub mod authenticated;
 pub mod bootstrap;
 pub mod config;
+pub mod config_watch;
 pub mod consumers;
 pub mod cors;
 pub mod db;
+pub mod error_reporter;
+pub mod http_client;
 pub mod jwt_authentication;
+pub mod jwt_keys;
 pub mod middleware;
 pub mod outbox;
+pub mod pagination;
+pub mod request_id;
 pub mod schema;
 pub mod swagger;
+pub mod token_store;
+pub mod validated_json;
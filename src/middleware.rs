@@ -1,66 +1,656 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, LazyLock, RwLock},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result, anyhow};
 use axum::{
-    extract::Request,
-    http::{StatusCode, header},
+    extract::{Request, State},
+    http::header,
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use futures::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::{
+    app_error::AppError,
+    authenticated::{self, AuthenticatedUser},
+    db::DbPool,
+    jwt_authentication::{self, ACCESS_TOKEN_COOKIE, Claims, Roles, TokenValidation},
+    jwt_keys::KeySet,
+    token_store,
+};
+
+static PATIENTS_POLICY: LazyLock<AuthPolicy> = LazyLock::new(|| AuthPolicy::new([Roles::Patient]));
+static DOCTORS_POLICY: LazyLock<AuthPolicy> = LazyLock::new(|| AuthPolicy::new([Roles::Doctor]));
+static STAFF_POLICY: LazyLock<AuthPolicy> = LazyLock::new(|| AuthPolicy::new([Roles::Staff]));
+static ADMINS_POLICY: LazyLock<AuthPolicy> = LazyLock::new(|| AuthPolicy::new([Roles::Admin]));
+static SERVICES_POLICY: LazyLock<AuthPolicy> = LazyLock::new(AuthPolicy::services);
+
+/// Where the access token is looked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenSource {
+    /// A cookie with the given name.
+    Cookie(String),
+    /// An `Authorization: Bearer <token>` header.
+    Bearer,
+}
+
+impl TokenSource {
+    pub fn cookie(name: impl Into<String>) -> Self {
+        Self::Cookie(name.into())
+    }
+
+    fn extract(&self, req: &Request) -> Option<String> {
+        match self {
+            TokenSource::Cookie(name) => {
+                let cookie_header = req.headers().get(header::COOKIE)?.to_str().ok()?;
+                get_cookie_value(cookie_header, name)
+            }
+            TokenSource::Bearer => {
+                let authorization = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+                get_bearer_token(authorization)
+            }
+        }
+    }
+}
 
-use crate::jwt_authentication;
+/// Verification keys of each role, read from the environment once and shared by
+/// every clone. `reload` re-reads them, e.g. after a key rotation; `bootstrap` calls
+/// it on every config reload for the keys it adds to the request extensions.
+///
+/// A role whose keys fail to load keeps failing with the same error until the load is
+/// retried, after a backoff that grows with every failure, so a misconfigured service
+/// does not re-read its config on every request but recovers once the keys appear.
+#[derive(Clone, Default)]
+pub struct RoleKeys {
+    keys: Arc<RwLock<HashMap<Roles, Loaded<Arc<KeySet>>>>>,
+}
 
-pub async fn patients_authorization(mut req: Request, next: Next) -> Result<Response, StatusCode> {
-    dotenvy::dotenv().ok();
-    let patient_secret =
-        std::env::var("JWT_PATIENT_SECRET").expect("JWT_PATIENT_SECRET is invalid");
+impl RoleKeys {
+    pub fn from_env(roles: impl IntoIterator<Item = Roles>) -> Result<Self> {
+        let keys = Self::default();
+        for role in roles {
+            keys.get(&role)?;
+        }
+        Ok(keys)
+    }
 
-    if let Some(cookie_header) = req.headers().get(header::COOKIE) {
-        if let Ok(cookie_str) = cookie_header.to_str() {
-            let access_token = get_cookie_value(cookie_str, "act");
+    pub fn insert(&self, role: Roles, keys: KeySet) {
+        self.keys
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(role, Loaded::Ready(Arc::new(keys)));
+    }
 
-            if let Some(token) = access_token {
-                if let Ok(claims) = jwt_authentication::verify_token(patient_secret, token) {
-                    if let Ok(patient_id) = claims.sub.parse::<i32>() {
-                        req.extensions_mut().insert(patient_id);
-                        return Ok(next.run(req).await);
-                    }
+    /// The keys of `role`, loaded on first use.
+    pub fn get(&self, role: &Roles) -> Result<Arc<KeySet>> {
+        let cached = self
+            .keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(role)
+            .cloned();
+        let loaded = match cached {
+            Some(loaded) if !loaded.is_due() => loaded,
+            previous => {
+                let loaded = Loaded::load(previous.as_ref(), || load_keys(role));
+                self.keys
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(role.clone(), loaded.clone());
+                loaded
+            }
+        };
+        loaded.get()
+    }
+
+    /// Re-reads the keys of every loaded role. On error the current keys are kept.
+    pub fn reload(&self) -> Result<()> {
+        let roles: Vec<Roles> = self
+            .keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect();
+
+        let mut reloaded = HashMap::new();
+        for role in roles {
+            let keys = KeySet::from_env(role.key_prefix())?;
+            reloaded.insert(role, Loaded::Ready(Arc::new(keys)));
+        }
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = reloaded;
+        Ok(())
+    }
+}
+
+fn load_keys(role: &Roles) -> Result<Arc<KeySet>> {
+    KeySet::from_env(role.key_prefix())
+        .map(Arc::new)
+        .with_context(|| format!("Failed to load {} keys", role.as_str()))
+}
+
+/// First wait before a failed load is retried; it doubles with every further failure.
+const LOAD_RETRY_MIN: Duration = Duration::from_secs(1);
+const LOAD_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// A value read from the environment, or the error of the last attempt and when to
+/// try again.
+#[derive(Clone)]
+enum Loaded<T> {
+    Ready(T),
+    Failed {
+        error: Arc<str>,
+        retry_at: Instant,
+        backoff: Duration,
+    },
+}
+
+impl<T: Clone> Loaded<T> {
+    fn load(previous: Option<&Self>, load: impl FnOnce() -> Result<T>) -> Self {
+        match load() {
+            Ok(value) => Loaded::Ready(value),
+            Err(e) => {
+                let backoff = match previous {
+                    Some(Loaded::Failed { backoff, .. }) => (*backoff * 2).min(LOAD_RETRY_MAX),
+                    _ => LOAD_RETRY_MIN,
+                };
+                Loaded::Failed {
+                    error: format!("{:#}", e).into(),
+                    retry_at: Instant::now() + backoff,
+                    backoff,
                 }
             }
         }
     }
 
-    Err(StatusCode::UNAUTHORIZED)
+    /// Whether a failed load should be attempted again.
+    fn is_due(&self) -> bool {
+        match self {
+            Loaded::Ready(_) => false,
+            Loaded::Failed { retry_at, .. } => Instant::now() >= *retry_at,
+        }
+    }
+
+    fn get(&self) -> Result<T> {
+        match self {
+            Loaded::Ready(value) => Ok(value.clone()),
+            Loaded::Failed { error, .. } => Err(anyhow!("{}", error)),
+        }
+    }
+}
+
+/// A `TokenValidation` shared by every clone, so it can be replaced on a config change.
+/// Read from the `JWT_*` settings in the environment on first use unless one was `set`;
+/// a failed read is retried like a failed key load.
+#[derive(Clone, Default)]
+pub struct SharedValidation {
+    validation: Arc<RwLock<Option<Loaded<TokenValidation>>>>,
 }
 
-pub async fn doctors_authorization(mut req: Request, next: Next) -> Result<Response, StatusCode> {
-    dotenvy::dotenv().ok();
-    let doctor_secret = std::env::var("JWT_DOCTOR_SECRET").expect("JWT_DOCTOR_SECRET is invalid");
+impl SharedValidation {
+    pub fn new(validation: TokenValidation) -> Self {
+        let shared = Self::default();
+        shared.set(validation);
+        shared
+    }
+
+    pub fn set(&self, validation: TokenValidation) {
+        *self.validation.write().unwrap_or_else(|e| e.into_inner()) =
+            Some(Loaded::Ready(validation));
+    }
+
+    pub fn get(&self) -> Result<TokenValidation> {
+        let cached = self
+            .validation
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let loaded = match cached {
+            Some(loaded) if !loaded.is_due() => loaded,
+            previous => {
+                let loaded = Loaded::load(previous.as_ref(), || {
+                    TokenValidation::from_env().context("Failed to load token validation")
+                });
+                *self.validation.write().unwrap_or_else(|e| e.into_inner()) = Some(loaded.clone());
+                loaded
+            }
+        };
+        loaded.get()
+    }
+}
+
+/// Which roles a route accepts and where the access token is read from.
+///
+/// Apply it with `AuthLayer`, or pass it as state to `authorization`:
+///
+/// ```ignore
+/// router.layer(AuthLayer::new(AuthPolicy::new([Roles::Patient, Roles::Doctor]))?)
+/// ```
+///
+/// By default the `act` cookie is tried first, then the `Authorization` header. A
+/// token that fails verification falls through to the next source, so a stale cookie
+/// does not hide a valid header. Revocation is checked against the pool given with
+/// `check_revocation`, else the one `bootstrap` adds to the request extensions.
+#[derive(Clone)]
+pub struct AuthPolicy {
+    roles: Arc<[Roles]>,
+    token_sources: Arc<[TokenSource]>,
+    revocation_pool: Option<DbPool>,
+    keys: Option<RoleKeys>,
+    env_keys: RoleKeys,
+    validation: Option<SharedValidation>,
+    env_validation: SharedValidation,
+}
+
+impl AuthPolicy {
+    pub fn new(roles: impl IntoIterator<Item = Roles>) -> Self {
+        Self {
+            roles: roles.into_iter().collect(),
+            token_sources: Arc::new([
+                TokenSource::cookie(ACCESS_TOKEN_COOKIE),
+                TokenSource::Bearer,
+            ]),
+            revocation_pool: None,
+            keys: None,
+            env_keys: RoleKeys::default(),
+            validation: None,
+            env_validation: SharedValidation::default(),
+        }
+    }
+
+    /// Accepts only `Service` tokens from the `Authorization` header, for internal routes
+    /// that other services call through `HttpClient`.
+    pub fn services() -> Self {
+        Self::new([Roles::Service]).token_sources([TokenSource::Bearer])
+    }
+
+    /// Rejects tokens whose family has been revoked in the `refresh_tokens` table of
+    /// `db_pool`, for routers that are not served through `bootstrap`.
+    pub fn check_revocation(mut self, db_pool: DbPool) -> Self {
+        self.revocation_pool = Some(db_pool);
+        self
+    }
+
+    /// Sets where the token is looked for, in the order the sources are tried.
+    pub fn token_sources(mut self, sources: impl IntoIterator<Item = TokenSource>) -> Self {
+        self.token_sources = sources.into_iter().collect();
+        self
+    }
+
+    /// Verifies tokens with `keys` instead of the ones `bootstrap` adds to the request
+    /// extensions and reloads on every config reload.
+    pub fn keys(mut self, keys: RoleKeys) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// The keys set on the policy, else the ones in the request extensions, else the
+    /// policy's own keys read from the environment.
+    fn role_keys(&self, req: &Request) -> RoleKeys {
+        self.keys
+            .as_ref()
+            .or_else(|| req.extensions().get::<RoleKeys>())
+            .unwrap_or(&self.env_keys)
+            .clone()
+    }
+
+    fn own_keys(&self) -> &RoleKeys {
+        self.keys.as_ref().unwrap_or(&self.env_keys)
+    }
+
+    /// Checks claims with `validation` instead of the one `bootstrap` adds to the
+    /// request extensions, which expects the service's name in `aud`.
+    pub fn validation(mut self, validation: SharedValidation) -> Self {
+        self.validation = Some(validation);
+        self
+    }
+
+    /// The validation set on the policy, else the one in the request extensions, else
+    /// the `JWT_*` settings, so routers built without `bootstrap` still work.
+    fn token_validation(&self, req: &Request) -> Result<TokenValidation> {
+        self.validation
+            .as_ref()
+            .or_else(|| req.extensions().get::<SharedValidation>())
+            .unwrap_or(&self.env_validation)
+            .get()
+    }
+
+    fn access_tokens(&self, req: &Request) -> Vec<String> {
+        let mut tokens: Vec<String> = Vec::new();
+        for token in self.token_sources.iter().filter_map(|s| s.extract(req)) {
+            if !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+        tokens
+    }
+
+    /// The first allowed role whose key verifies `token`, with the token's claims.
+    fn verify(
+        &self,
+        token: &str,
+        validation: &TokenValidation,
+        role_keys: &RoleKeys,
+    ) -> Result<Option<(Roles, Claims)>, AppError> {
+        for role in self.roles.iter() {
+            let keys = role_keys.get(role)?;
+            if let Ok(claims) =
+                jwt_authentication::verify_access_token(&keys, validation, role, token)
+            {
+                return Ok(Some((role.clone(), claims)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks the access token of `req` and returns the request with the token's
+    /// `Claims` and the resolved `AuthenticatedUser` in its extensions.
+    ///
+    /// The token must verify against the key of an allowed role, carry that role and
+    /// pass the policy's `TokenValidation`.
+    pub async fn authorize(&self, mut req: Request) -> Result<Request, AppError> {
+        let tokens = self.access_tokens(&req);
+        if tokens.is_empty() {
+            return Err(AppError::Unauthorized("Missing access token".into()));
+        }
+        let validation = self.token_validation(&req)?;
+        let role_keys = self.role_keys(&req);
+        let revocation_pool = self
+            .revocation_pool
+            .clone()
+            .or_else(|| req.extensions().get::<DbPool>().cloned());
 
-    if let Some(cookie_header) = req.headers().get(header::COOKIE) {
-        if let Ok(cookie_str) = cookie_header.to_str() {
-            let access_token = get_cookie_value(cookie_str, "act");
+        let mut rejection = "Invalid or expired access token";
+        for token in tokens {
+            let Some((role, claims)) = self.verify(&token, &validation, &role_keys)? else {
+                continue;
+            };
 
-            if let Some(token) = access_token {
-                if let Ok(claims) = jwt_authentication::verify_token(doctor_secret, token) {
-                    if let Ok(doctor_id) = claims.sub.parse::<i32>() {
-                        req.extensions_mut().insert(doctor_id);
-                        return Ok(next.run(req).await);
-                    }
+            if let Some(db_pool) = &revocation_pool {
+                let conn = &mut db_pool
+                    .get()
+                    .await
+                    .map_err(|_| AppError::ServiceUnreachable("database".into()))?;
+                if token_store::is_revoked(conn, &claims).await? {
+                    rejection = "Access token has been revoked";
+                    continue;
                 }
             }
+
+            // Deprecated: the bare user id is still inserted for handlers that take
+            // `Extension<i32>`; use `Authenticated` instead. Removed in the next release.
+            if let Ok(user_id) = claims.sub.parse::<i32>() {
+                req.extensions_mut().insert(user_id);
+            }
+            req.extensions_mut().insert(AuthenticatedUser {
+                role,
+                subject: claims.sub.clone(),
+            });
+            req.extensions_mut().insert(claims);
+            return Ok(req);
+        }
+
+        Err(AppError::Unauthorized(rejection.into()))
+    }
+}
+
+/// Layer that applies an `AuthPolicy` to every request of the wrapped service.
+///
+/// The keys of the policy's roles are loaded when the layer is built, so a missing or
+/// invalid key fails at startup instead of on the first request.
+#[derive(Clone)]
+pub struct AuthLayer {
+    policy: AuthPolicy,
+}
+
+impl AuthLayer {
+    pub fn new(policy: AuthPolicy) -> Result<Self> {
+        for role in policy.roles.iter() {
+            policy.own_keys().get(role)?;
+        }
+        if let Some(validation) = &policy.validation {
+            validation.get()?;
         }
+        Ok(Self { policy })
     }
 
-    Err(StatusCode::UNAUTHORIZED)
+    /// The keys used by this layer, e.g. to `reload` them on a config change.
+    pub fn keys(&self) -> &RoleKeys {
+        self.policy.own_keys()
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    policy: AuthPolicy,
 }
 
+impl<S> Service<Request> for AuthService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Use the service that was driven to readiness and leave a fresh clone behind.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let policy = self.policy.clone();
+
+        Box::pin(async move {
+            match policy.authorize(req).await {
+                Ok(req) => inner.call(req).await,
+                Err(e) => Ok(e.into_response()),
+            }
+        })
+    }
+}
+
+/// Guard for routes behind an auth layer that need a scope on top of the role:
+///
+/// ```ignore
+/// Router::new()
+///     .route("/prescriptions", post(prescribe))
+///     .route_layer(require_scope("prescriptions:write"))
+///     .route_layer(AuthLayer::new(AuthPolicy::new([Roles::Doctor]))?)
+/// ```
+///
+/// Requests without the scope get `AppError::ForbiddenResource`, and requests that
+/// were never authenticated `AppError::Unauthorized`.
+pub fn require_scope(scope: impl Into<Arc<str>>) -> RequireScopeLayer {
+    RequireScopeLayer {
+        scope: scope.into(),
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireScopeLayer {
+    scope: Arc<str>,
+}
+
+impl<S> Layer<S> for RequireScopeLayer {
+    type Service = RequireScope<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireScope {
+            inner,
+            scope: self.scope.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireScope<S> {
+    inner: S,
+    scope: Arc<str>,
+}
+
+impl<S> Service<Request> for RequireScope<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let allowed = match req.extensions().get::<Claims>() {
+            Some(claims) => authenticated::ensure_scope(claims, &self.scope),
+            None => Err(AppError::Unauthorized(
+                "Request has not been authenticated".into(),
+            )),
+        };
+
+        match allowed {
+            Ok(()) => {
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+                Box::pin(async move { inner.call(req).await })
+            }
+            Err(e) => Box::pin(async move { Ok(e.into_response()) }),
+        }
+    }
+}
+
+/// `AuthPolicy::authorize` as an axum middleware function.
+pub async fn authorization(
+    State(policy): State<AuthPolicy>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let req = policy.authorize(req).await?;
+    Ok(next.run(req).await)
+}
+
+pub async fn patients_authorization(req: Request, next: Next) -> Result<Response, AppError> {
+    authorization(State(PATIENTS_POLICY.clone()), req, next).await
+}
+
+pub async fn doctors_authorization(req: Request, next: Next) -> Result<Response, AppError> {
+    authorization(State(DOCTORS_POLICY.clone()), req, next).await
+}
+
+pub async fn staff_authorization(req: Request, next: Next) -> Result<Response, AppError> {
+    authorization(State(STAFF_POLICY.clone()), req, next).await
+}
+
+pub async fn admins_authorization(req: Request, next: Next) -> Result<Response, AppError> {
+    authorization(State(ADMINS_POLICY.clone()), req, next).await
+}
+
+pub async fn services_authorization(req: Request, next: Next) -> Result<Response, AppError> {
+    authorization(State(SERVICES_POLICY.clone()), req, next).await
+}
+
+/// Finds a cookie by name. Separators may or may not be followed by whitespace,
+/// and a value wrapped in double quotes is unquoted.
 fn get_cookie_value(cookie_header: &str, key: &str) -> Option<String> {
-    cookie_header.split("; ").find_map(|cookie| {
-        let mut parts = cookie.splitn(2, "=");
-        let name = parts.next()?.trim();
-        let value = parts.next()?.trim();
-        if name == key {
-            Some(value.to_string())
-        } else {
-            None
+    cookie_header.split(';').find_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        if name.trim() != key {
+            return None;
         }
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        (!value.is_empty()).then(|| value.to_string())
     })
 }
+
+fn get_bearer_token(authorization: &str) -> Option<String> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then(|| token.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: &str, authorization: Option<&str>) -> Request {
+        let mut builder = Request::builder().header(header::COOKIE, cookie);
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(axum::body::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn cookie_value_is_found_among_other_cookies() {
+        assert_eq!(
+            get_cookie_value("theme=dark;act=abc.def; rft=\"xyz\"", "act").as_deref(),
+            Some("abc.def")
+        );
+        assert_eq!(
+            get_cookie_value("act=abc; rft=\"xyz\"", "rft").as_deref(),
+            Some("xyz")
+        );
+        assert_eq!(get_cookie_value("react=abc", "act"), None);
+        assert_eq!(get_cookie_value("act=", "act"), None);
+    }
+
+    #[test]
+    fn failed_loads_are_retried_with_a_growing_backoff() {
+        let failed = Loaded::<u8>::load(None, || Err(anyhow!("secret not mounted")));
+        assert!(!failed.is_due());
+        assert_eq!(failed.get().unwrap_err().to_string(), "secret not mounted");
+
+        let again = Loaded::load(Some(&failed), || Err(anyhow!("secret not mounted")));
+        let Loaded::Failed { backoff, .. } = again else {
+            panic!("expected a failed load");
+        };
+        assert_eq!(backoff, LOAD_RETRY_MIN * 2);
+
+        let due = Loaded::<u8>::Failed {
+            error: "secret not mounted".into(),
+            retry_at: Instant::now(),
+            backoff: LOAD_RETRY_MAX,
+        };
+        assert!(due.is_due());
+        assert_eq!(Loaded::load(Some(&due), || Ok(7)).get().unwrap(), 7);
+    }
+
+    #[test]
+    fn every_token_source_is_collected_in_order() {
+        let policy = AuthPolicy::new([Roles::Patient]);
+
+        let req = request("act=stale", Some("Bearer fresh"));
+        assert_eq!(policy.access_tokens(&req), ["stale", "fresh"]);
+
+        let req = request("act=same", Some("bearer same"));
+        assert_eq!(policy.access_tokens(&req), ["same"]);
+    }
+}
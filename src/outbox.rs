@@ -8,10 +8,15 @@ use diesel::{
     prelude::{Insertable, Queryable},
 };
 use diesel_async::{AsyncConnection, RunQueryDsl};
+use lapin::{
+    BasicProperties, Connection, ConnectionProperties,
+    options::BasicPublishOptions,
+    types::{AMQPValue, FieldTable},
+};
 use serde::Serialize;
 use tracing::{error, info};
 
-use crate::{app_state::AppState, schema::outbox};
+use crate::{app_state::AppState, request_id::RequestId, schema::outbox};
 
 #[derive(Queryable, Selectable, Debug, Serialize)]
 #[diesel(table_name = crate::schema::outbox)]
@@ -23,6 +28,7 @@ pub struct OutboxEntity {
     pub status: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub request_id: Option<String>,
 }
 
 #[derive(Queryable, Insertable, Selectable, Debug)]
@@ -31,6 +37,7 @@ pub struct OutboxEntity {
 pub struct CreateOutboxEntity {
     pub event_type: String,
     pub payload: String,
+    pub request_id: Option<String>,
 }
 
 pub fn init(state: Arc<AppState>) {
@@ -50,6 +57,14 @@ async fn start(state: Arc<AppState>) -> anyhow::Result<()> {
     let conn = &mut state.db_pool.get().await?;
     let channel = state.rmq_client.create_channel().await?;
 
+    // Publishing goes through lapin directly so that message headers can be set;
+    // `rmq_wrappers` only publishes plain payloads.
+    let rmq_url = state.config.current().message_queue.url.clone();
+    let publisher = Connection::connect(rmq_url.expose(), ConnectionProperties::default())
+        .await?
+        .create_channel()
+        .await?;
+
     loop {
         info!("Processing outbox...");
 
@@ -64,8 +79,17 @@ async fn start(state: Arc<AppState>) -> anyhow::Result<()> {
             tokio::time::sleep(Duration::from_secs(5)).await;
         } else {
             for event in events {
-                let queue = channel.create_queue(&event.event_type).await?;
-                match queue.publish_plain(&event.payload).await {
+                channel.create_queue(&event.event_type).await?;
+                let published = publisher
+                    .basic_publish(
+                        "",
+                        &event.event_type,
+                        BasicPublishOptions::default(),
+                        event.payload.as_bytes(),
+                        message_properties(&event),
+                    )
+                    .await;
+                match published {
                     Ok(_) => {
                         diesel::update(outbox::table.filter(outbox::id.eq(event.id)))
                             .set(outbox::status.eq("PROCESSED"))
@@ -90,6 +114,21 @@ async fn start(state: Arc<AppState>) -> anyhow::Result<()> {
     }
 }
 
+fn message_properties(event: &OutboxEntity) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    if let Some(request_id) = &event.request_id {
+        headers.insert(
+            "x-request-id".into(),
+            AMQPValue::LongString(request_id.as_str().into()),
+        );
+    }
+    BasicProperties::default().with_headers(headers)
+}
+
+/// Stores an event in the outbox. Call it inside the same transaction as the domain write.
+///
+/// The current request id, if any, is stored with the event and sent as the
+/// `x-request-id` message header.
 pub async fn publish<C, P>(conn: &mut C, event_type: String, payload: P) -> Result<OutboxEntity>
 where
     C: AsyncConnection<Backend = Pg>,
@@ -97,8 +136,9 @@ where
 {
     let outbox = diesel::insert_into(outbox::table)
         .values(CreateOutboxEntity {
-            event_type: event_type,
+            event_type,
             payload: serde_json::to_string(&payload).context("Failed to serialize payload")?,
+            request_id: RequestId::current().map(|id| id.to_string()),
         })
         .returning(OutboxEntity::as_returning())
         .get_result(conn)
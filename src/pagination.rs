@@ -0,0 +1,252 @@
+use axum::{
+    extract::{FromRef, FromRequestParts, Query},
+    http::request::Parts,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use diesel::{
+    dsl::{self, Limit, Offset},
+    expression::{AsExpression, Expression},
+    prelude::ExpressionMethods,
+    query_dsl::methods::{FilterDsl, LimitDsl, OffsetDsl},
+    sql_types::SqlType,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    app_error::{AppError, FieldError},
+    app_state::AppState,
+};
+
+/// Query parameters accepted by `Pagination`. Reference it in `#[utoipa::path]` with
+/// `params(PaginationQuery)`.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PaginationQuery {
+    /// 1-based page number. Ignored when `cursor` is given.
+    pub page: Option<i64>,
+    /// Items per page, up to the service's maximum.
+    pub page_size: Option<i64>,
+    /// `next_cursor` of the previous page, for keyset pagination.
+    pub cursor: Option<String>,
+}
+
+/// Validated page request, extracted from `?page=&page_size=&cursor=`.
+///
+/// Offset pagination uses `page` with `paginate`; keyset pagination uses `cursor`
+/// with `seek_after` and `keyset_limit`.
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    pub page: i64,
+    pub page_size: i64,
+    pub cursor: Option<String>,
+}
+
+impl<S> FromRequestParts<S> for Pagination
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?;
+        let limits = AppState::from_ref(state)
+            .config
+            .current()
+            .pagination
+            .clone();
+
+        let page = query.page.unwrap_or(1);
+        let page_size = query.page_size.unwrap_or(limits.default_page_size);
+
+        let mut errors = Vec::new();
+        if page < 1 {
+            errors.push(invalid("page", "must be at least 1".into()));
+        }
+        if !(1..=limits.max_page_size).contains(&page_size) {
+            errors.push(invalid(
+                "page_size",
+                format!("must be between 1 and {}", limits.max_page_size),
+            ));
+        } else if (page - 1).checked_mul(page_size).is_none() {
+            errors.push(invalid("page", "is too large".into()));
+        }
+        if !errors.is_empty() {
+            return Err(AppError::ValidationFailed(errors));
+        }
+
+        Ok(Self {
+            page,
+            page_size,
+            cursor: query.cursor,
+        })
+    }
+}
+
+fn invalid(field: &str, message: String) -> FieldError {
+    FieldError {
+        field: field.into(),
+        code: "range".into(),
+        message: Some(message),
+    }
+}
+
+impl Pagination {
+    pub fn limit(&self) -> i64 {
+        self.page_size
+    }
+
+    /// Rows before the page. Saturates instead of overflowing for a page too large for
+    /// `i64`, which the extractor already rejects.
+    pub fn offset(&self) -> i64 {
+        (self.page - 1).saturating_mul(self.page_size)
+    }
+
+    /// Limit for keyset queries: one row more than the page, so `PaginatedResponse::keyset`
+    /// can tell whether another page follows.
+    pub fn keyset_limit(&self) -> i64 {
+        self.page_size + 1
+    }
+
+    /// Applies `LIMIT` and `OFFSET` for the requested page.
+    pub fn paginate<Q>(&self, query: Q) -> Offset<Limit<Q>>
+    where
+        Q: LimitDsl,
+        Limit<Q>: OffsetDsl,
+    {
+        query.limit(self.limit()).offset(self.offset())
+    }
+
+    /// Decodes the cursor into the key of the last row of the previous page.
+    pub fn cursor<K: DeserializeOwned>(&self) -> Result<Option<K>, AppError> {
+        self.cursor
+            .as_deref()
+            .map(decode_cursor)
+            .transpose()
+            .map_err(|_| {
+                AppError::ValidationFailed(vec![FieldError {
+                    field: "cursor".into(),
+                    code: "invalid".into(),
+                    message: Some("is not a cursor returned by this endpoint".into()),
+                }])
+            })
+    }
+}
+
+/// One page of results.
+///
+/// Offset pages carry `page` and `total`; keyset pages carry `next_cursor`, which is
+/// `None` on the last page.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PaginatedResponse<T: ToSchema> {
+    pub items: Vec<T>,
+    pub page_size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T: ToSchema> PaginatedResponse<T> {
+    pub fn offset(items: Vec<T>, total: i64, pagination: &Pagination) -> Self {
+        Self {
+            items,
+            page_size: pagination.page_size,
+            page: Some(pagination.page),
+            total: Some(total),
+            next_cursor: None,
+        }
+    }
+
+    /// Builds a keyset page from rows fetched with `keyset_limit`. `key` returns the
+    /// value the next page continues after, usually the sort column.
+    pub fn keyset<K, F>(mut rows: Vec<T>, pagination: &Pagination, key: F) -> Self
+    where
+        K: Serialize,
+        F: Fn(&T) -> K,
+    {
+        let has_more = rows.len() as i64 > pagination.page_size;
+        rows.truncate(pagination.page_size as usize);
+
+        let next_cursor = match has_more {
+            true => rows.last().map(|row| encode_cursor(&key(row))),
+            false => None,
+        };
+        Self {
+            items: rows,
+            page_size: pagination.page_size,
+            page: None,
+            total: None,
+            next_cursor,
+        }
+    }
+}
+
+/// Restricts `query` to rows after `after` on `column`, for keyset pagination ordered
+/// by `column` ascending. Without a cursor the query is returned unchanged.
+///
+/// ```ignore
+/// let query = appointments::table.order(appointments::id.asc()).into_boxed();
+/// let rows = pagination::seek_after(query, appointments::id, pagination.cursor::<i32>()?)
+///     .limit(pagination.keyset_limit())
+///     .load::<Appointment>(conn)
+///     .await?;
+/// let page = PaginatedResponse::keyset(rows, &pagination, |row| row.id);
+/// ```
+pub fn seek_after<Q, C, V>(query: Q, column: C, after: Option<V>) -> Q
+where
+    Q: FilterDsl<dsl::Gt<C, V>, Output = Q>,
+    C: ExpressionMethods,
+    C::SqlType: SqlType,
+    V: AsExpression<<C as Expression>::SqlType>,
+{
+    match after {
+        Some(after) => query.filter(column.gt(after)),
+        None => query,
+    }
+}
+
+pub fn encode_cursor<K: Serialize>(key: &K) -> String {
+    // Serializing plain keys to JSON does not fail.
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(key).unwrap_or_default())
+}
+
+pub fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> anyhow::Result<K> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_of_a_huge_page_does_not_overflow() {
+        let pagination = Pagination {
+            page: i64::MAX,
+            page_size: 100,
+            cursor: None,
+        };
+        assert_eq!(pagination.offset(), i64::MAX);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let key = (42, "2026-10-18T09:00:00Z".to_string());
+        let cursor = encode_cursor(&key);
+
+        assert!(!cursor.contains(['+', '/', '=']));
+        assert_eq!(decode_cursor::<(i32, String)>(&cursor).unwrap(), key);
+    }
+
+    #[test]
+    fn foreign_cursors_are_rejected() {
+        assert!(decode_cursor::<i32>("not base64!").is_err());
+        assert!(decode_cursor::<i32>(&encode_cursor(&"text")).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{convert::Infallible, fmt};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{HeaderName, HeaderValue, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Incoming ids longer than this are discarded and replaced with a generated one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+/// Correlation id of the request (or message) currently being handled.
+///
+/// Handlers can take it as an extractor. Outgoing calls made through
+/// `AppState.http_client` and rows written by `outbox::publish` pick it up automatically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accepts an id coming from a client or an upstream service,
+    /// rejecting anything that is empty, too long or not printable ASCII.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let valid = !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the id of the request being handled by the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_REQUEST_ID.try_with(|id| id.clone()).ok()
+    }
+
+    /// Runs `future` with this id as the current request id.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_REQUEST_ID.scope(self, future).await
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .or_else(RequestId::current)
+            .unwrap_or_else(RequestId::generate))
+    }
+}
+
+/// Accepts the caller's `X-Request-Id` (or generates one), exposes it to the rest of
+/// the stack and echoes it back in the response.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+
+    req.extensions_mut().insert(request_id.clone());
+
+    let mut response = request_id.clone().scope(next.run(req)).await;
+
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
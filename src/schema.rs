@@ -8,7 +8,21 @@ diesel::table! {
         status -> Text,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        request_id -> Nullable<Text>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(outbox,);
+diesel::table! {
+    refresh_tokens (jti) {
+        jti -> Uuid,
+        family_id -> Uuid,
+        subject -> Text,
+        role -> Text,
+        expires_at -> Timestamptz,
+        rotated_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(outbox, refresh_tokens,);
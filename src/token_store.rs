@@ -0,0 +1,252 @@
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::{
+    ExpressionMethods, OptionalExtension, QueryDsl, Selectable, SelectableHelper,
+    dsl::{exists, select},
+    pg::Pg,
+    prelude::{Insertable, Queryable},
+};
+use diesel_async::{AsyncConnection, RunQueryDsl, scoped_futures::ScopedFutureExt};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    app_error::AppError,
+    jwt_authentication::{self, Claims, Passport, Roles, TokenKeys, TokenSettings},
+    schema::refresh_tokens,
+};
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RefreshTokenEntity {
+    pub jti: Uuid,
+    pub family_id: Uuid,
+    pub subject: String,
+    pub role: String,
+    pub expires_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateRefreshTokenEntity {
+    pub jti: Uuid,
+    pub family_id: Uuid,
+    pub subject: String,
+    pub role: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Issues a passport for a new login and records its refresh token.
+pub async fn issue_passport<C>(
+    conn: &mut C,
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    sub: String,
+    scopes: Vec<String>,
+) -> Result<Passport, AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    let family = Uuid::new_v4().to_string();
+    let (passport, refresh_claims) =
+        jwt_authentication::issue_passport_in_family(keys, settings, sub, scopes, &family)?;
+    record(conn, &refresh_claims).await?;
+    Ok(passport)
+}
+
+/// Exchanges a refresh token for a new pair carrying the scopes `current_scopes`
+/// returns for the token's subject, so revoked scopes are not carried over.
+///
+/// A refresh token can be used once. Presenting one that was already rotated means
+/// it has leaked, so the whole token family is revoked and the caller is rejected.
+///
+/// The lookup, the rotation and the new token's record run in one transaction, so a
+/// failure half-way leaves the old token usable for a retry instead of turning the
+/// retry into a reuse.
+pub async fn rotate_passport<C>(
+    conn: &mut C,
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    refresh_token: &str,
+    current_scopes: impl AsyncFnOnce(&str) -> Result<Vec<String>, AppError>,
+) -> Result<Passport, AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    let claims = jwt_authentication::verify_refresh_token(keys, settings, refresh_token)
+        .map_err(|_| AppError::Unauthorized("Invalid refresh token".into()))?;
+    // A token issued before rotation existed has no record, so its reuse could not be
+    // detected; its owner has to log in again.
+    let Some(jti) = claims.jti.as_deref() else {
+        return Err(AppError::Unauthorized(
+            "Refresh token is no longer supported, log in again".into(),
+        ));
+    };
+    let jti = parse_id(Some(jti))?;
+    let scopes = current_scopes(&claims.sub).await?;
+
+    let rotation = conn
+        .transaction::<_, AppError, _>(|conn| {
+            async move {
+                let token: RefreshTokenEntity = refresh_tokens::table
+                    .find(jti)
+                    .select(RefreshTokenEntity::as_select())
+                    .first(conn)
+                    .await
+                    .optional()?
+                    .ok_or_else(|| AppError::Unauthorized("Unknown refresh token".into()))?;
+
+                if token.revoked_at.is_some() {
+                    return Err(AppError::Unauthorized(
+                        "Refresh token has been revoked".into(),
+                    ));
+                }
+
+                // Guarded update, so two concurrent uses of the same token cannot both succeed.
+                let rotated = diesel::update(
+                    refresh_tokens::table
+                        .filter(refresh_tokens::jti.eq(jti))
+                        .filter(refresh_tokens::rotated_at.is_null()),
+                )
+                .set(refresh_tokens::rotated_at.eq(Utc::now()))
+                .execute(conn)
+                .await?;
+
+                if rotated == 0 {
+                    warn!(
+                        "Refresh token reuse detected for {} {}, revoking family {}",
+                        token.role, token.subject, token.family_id
+                    );
+                    // Returned as a value so the revocation is committed.
+                    revoke_family(conn, token.family_id).await?;
+                    return Ok(Rotation::Reused);
+                }
+
+                let (passport, refresh_claims) = jwt_authentication::issue_passport_in_family(
+                    keys,
+                    settings,
+                    claims.sub.clone(),
+                    scopes,
+                    &token.family_id.to_string(),
+                )?;
+                record(conn, &refresh_claims).await?;
+                Ok(Rotation::Rotated(passport))
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    match rotation {
+        Rotation::Rotated(passport) => Ok(passport),
+        Rotation::Reused => Err(AppError::Unauthorized(
+            "Refresh token has already been used".into(),
+        )),
+    }
+}
+
+enum Rotation {
+    Rotated(Passport),
+    Reused,
+}
+
+/// Revokes the family of `refresh_token`, e.g. on logout.
+pub async fn revoke_refresh_token<C>(
+    conn: &mut C,
+    keys: &TokenKeys,
+    settings: &TokenSettings,
+    refresh_token: &str,
+) -> Result<(), AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    let claims = jwt_authentication::verify_refresh_token(keys, settings, refresh_token)
+        .map_err(|_| AppError::Unauthorized("Invalid refresh token".into()))?;
+    match claims.fam {
+        Some(family) => revoke_family(conn, parse_id(Some(&family))?).await,
+        // Tokens from before rotation existed have no family to revoke.
+        None => Ok(()),
+    }
+}
+
+pub async fn revoke_family<C>(conn: &mut C, family_id: Uuid) -> Result<(), AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::family_id.eq(family_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(Utc::now()))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Revokes every session of a user, e.g. when an account is banned.
+pub async fn revoke_subject<C>(conn: &mut C, sub: &str, role: &Roles) -> Result<(), AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::subject.eq(sub))
+            .filter(refresh_tokens::role.eq(role.as_str()))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(Utc::now()))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Whether the family a token belongs to has been revoked.
+pub async fn is_revoked<C>(conn: &mut C, claims: &Claims) -> Result<bool, AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    let Some(family) = claims.fam.as_deref() else {
+        return Ok(false);
+    };
+    let family_id = parse_id(Some(family))?;
+    let revoked = select(exists(
+        refresh_tokens::table
+            .filter(refresh_tokens::family_id.eq(family_id))
+            .filter(refresh_tokens::revoked_at.is_not_null()),
+    ))
+    .get_result(conn)
+    .await?;
+    Ok(revoked)
+}
+
+async fn record<C>(conn: &mut C, refresh_claims: &Claims) -> Result<(), AppError>
+where
+    C: AsyncConnection<Backend = Pg>,
+{
+    let expires_at = Utc
+        .timestamp_opt(refresh_claims.exp as i64, 0)
+        .single()
+        .context("Refresh token expiry is out of range")?;
+
+    diesel::insert_into(refresh_tokens::table)
+        .values(CreateRefreshTokenEntity {
+            jti: parse_id(refresh_claims.jti.as_deref())?,
+            family_id: parse_id(refresh_claims.fam.as_deref())?,
+            subject: refresh_claims.sub.clone(),
+            role: refresh_claims.role.as_str().to_string(),
+            expires_at,
+        })
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+fn parse_id(id: Option<&str>) -> Result<Uuid, AppError> {
+    id.and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(|| AppError::Unauthorized("Malformed token id".into()))
+}
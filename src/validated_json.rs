@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    Json,
+    extract::{FromRequest, Request, rejection::JsonRejection},
+};
+use serde::de::DeserializeOwned;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+use crate::app_error::{AppError, FieldError};
+
+/// JSON body that is deserialized and then checked against the `validator` rules
+/// declared on `T`. Every failed rule is reported in a single 422 response.
+///
+/// ```ignore
+/// #[derive(Deserialize, Validate)]
+/// struct CreateAppointment {
+///     #[validate(length(min = 1, max = 500))]
+///     reason: String,
+/// }
+///
+/// async fn create(ValidatedJson(body): ValidatedJson<CreateAppointment>) { ... }
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValidatedJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let JsonBody(value) = JsonBody::<T>::from_request(req, state).await?;
+        value.validate()?;
+        Ok(Self(value))
+    }
+}
+
+/// `axum::Json` whose rejections are `AppError`s, so a malformed body gets the same
+/// error shape as every other error instead of axum's plain-text response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonBody<T>(pub T);
+
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            // The body is valid JSON but does not fit the type, e.g. a missing field.
+            JsonRejection::JsonDataError(e) => AppError::ValidationFailed(vec![FieldError {
+                field: "body".into(),
+                code: "invalid".into(),
+                message: Some(e.body_text()),
+            }]),
+            rejection => AppError::BadRequest(rejection.body_text()),
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        collect_field_errors(&errors, "", &mut fields);
+        AppError::ValidationFailed(fields)
+    }
+}
+
+/// Flattens nested struct and list errors into dotted field paths.
+fn collect_field_errors(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    let path = |field: &str| match prefix.is_empty() {
+        true => field.to_string(),
+        false => format!("{}.{}", prefix, field),
+    };
+
+    // Sorted so the response does not depend on hash map order.
+    let sorted: BTreeMap<_, _> = errors.errors().iter().collect();
+    for (field, kind) in sorted {
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                out.extend(errors.iter().map(|error| FieldError {
+                    field: path(field),
+                    code: error.code.to_string(),
+                    message: error.message.as_ref().map(|m| m.to_string()),
+                }));
+            }
+            ValidationErrorsKind::Struct(errors) => {
+                collect_field_errors(errors, &path(field), out);
+            }
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_field_errors(errors, &format!("{}[{}]", path(field), index), out);
+                }
+            }
+        }
+    }
+}